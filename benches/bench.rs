@@ -3,6 +3,8 @@ extern crate criterion;
 extern crate bincode;
 extern crate hgi;
 extern crate image;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 
 use image::GrayImage;
 
@@ -16,9 +18,11 @@ fn get_test_image(width: u32, height: u32, levels: usize) -> (Metadata, GrayImag
     let metadata = Metadata {
         quantization_level: QuantizationLevel::Medium,
         interpolation: InterpolationType::Crossed,
-        width: width,
-        height: height,
+        width,
+        height,
         scale_level: levels,
+        error_map: None,
+        tiling: None,
     };
 
     let mut imgbuf = GrayImage::new(width, height);
@@ -42,8 +46,7 @@ fn benchmarks(c: &mut Criterion) {
             for (i, x) in v.iter_mut().enumerate() {
                 *x = i as u8;
             }
-            let mut mem: Vec<u8> = Vec::with_capacity(size as usize);
-            unsafe { mem.set_len(size as usize) };
+            let mut mem = vec![0u8; size as usize];
 
             bencher.iter(|| {
                 unsafe { ::std::ptr::copy_nonoverlapping(v.as_ptr(), mem.as_mut_ptr(), v.len()) };
@@ -102,10 +105,10 @@ fn benchmarks(c: &mut Criterion) {
             let interpolator = Crossed;
             let quantizator = Linear::from(QuantizationLevel::Lossless);
             let mut encoder = Encoder::new(interpolator, quantizator, levels);
-            let grid = encoder.encode(image);
+            let grid = encoder.encode(image).unwrap();
             let mut decoder = Decoder::new(Crossed);
 
-            bencher.iter_with_large_drop(|| decoder.decode((width, height), levels, &grid));
+            bencher.iter_with_large_drop(|| decoder.decode((width, height), levels, &grid).unwrap());
         }).throughput(Throughput::Bytes(size)),
     );
 
@@ -114,7 +117,7 @@ fn benchmarks(c: &mut Criterion) {
         let interpolator = Crossed;
         let quantizator = Linear::from(QuantizationLevel::Lossless);
         let mut encoder = Encoder::new(interpolator, quantizator, levels);
-        let grid = encoder.encode(image);
+        let grid = encoder.encode(image).unwrap();
         let archive = Archive { metadata, grid };
         let serialized_size = bincode::serialized_size(&archive).unwrap() as usize;
 
@@ -140,7 +143,7 @@ fn benchmarks(c: &mut Criterion) {
                 )
             },
             |(mut buffer, image)| {
-                let grid = encoder.encode(image);
+                let grid = encoder.encode(image).unwrap();
                 let archive = Archive {
                     metadata: metadata.clone(),
                     grid,
@@ -151,9 +154,70 @@ fn benchmarks(c: &mut Criterion) {
     });
 }
 
+// Tiled encoding and decoding on 1, 2, 4 and 8 threads
+#[cfg(feature = "parallel")]
+fn thread_scaling(c: &mut Criterion) {
+    use hgi::tiling::Tiling;
+    use hgi::{ArchiveReader, ArchiveWriter};
+
+    let (width, height) = (1920u32, 1080u32);
+    let size = width * height;
+    let levels = 4;
+    let (metadata, image) = get_test_image(width, height, levels);
+    let metadata = Metadata {
+        tiling: Some(Tiling::new(256, 256).unwrap()),
+        ..metadata
+    };
+
+    for &threads in &[1, 2, 4, 8] {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let (metadata, image) = (metadata.clone(), image.clone());
+        let name = format!("tiled_encode_{}_threads", threads);
+        c.bench(
+            "thread_scaling",
+            Benchmark::new(name, move |bencher| {
+                let mut encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+                bencher.iter(|| {
+                    pool.install(|| {
+                        let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+                        encoder.encode_tiles(&image, &mut writer).unwrap();
+                        writer.finish().unwrap()
+                    })
+                });
+            }).throughput(Throughput::Bytes(size)),
+        );
+    }
+
+    let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+    let mut encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+    encoder.encode_tiles(&image, &mut writer).unwrap();
+    let buffer = writer.finish().unwrap();
+
+    for &threads in &[1, 2, 4, 8] {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let buffer = buffer.clone();
+        let name = format!("tiled_decode_{}_threads", threads);
+        c.bench(
+            "thread_scaling",
+            Benchmark::new(name, move |bencher| {
+                let mut decoder = Decoder::new(Crossed);
+                bencher.iter_with_large_drop(|| {
+                    pool.install(|| {
+                        let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+                        decoder.decode_tiles(&mut reader).unwrap()
+                    })
+                });
+            }).throughput(Throughput::Bytes(size)),
+        );
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+fn thread_scaling(_c: &mut Criterion) {}
+
 criterion_group!(
     name = benches;
     config = Criterion::default().sample_size(25);
-    targets = benchmarks
+    targets = benchmarks, thread_scaling
 );
 criterion_main!(benches);
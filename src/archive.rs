@@ -1,16 +1,46 @@
-use std::error::Error;
-use std::io::{Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 use bincode;
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
+use crc32fast::Hasher;
 use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-
+use serde::Deserialize;
+use error::{corrupted, invalid, invalid_dimensions, truncated, Error};
+use error_map::ErrorMap;
+use grid::Grid;
 use interpolator::InterpolationType;
 use quantizator::QuantizationLevel;
+use tiling::{Rect, Tiling};
+
+// Archives written before the container format: MAGIC_V0, bincode header, deflated grid
+pub const MAGIC_V0: u32 = 0xBAAD_A555;
+pub const MAGIC: u32 = 0xBAAD_A557;
+pub const FORMAT_VERSION: u16 = 1;
+/// Steps of the first level must fit into `u32`
+pub const MAX_SCALE_LEVEL: usize = 31;
+
+// Chunks with an uppercase first letter are critical and must be understood by the reader,
+// the rest are ancillary and skipped when unknown
+pub type Tag = [u8; 4];
+pub const LEVEL: Tag = *b"LEVL";
+// Critical, since the header quantization level doesn't bound the error of an archive with a map
+pub const ERROR_MAP: Tag = *b"EMap";
+// Ancillary error map of earlier archives, still read
+pub const LEGACY_ERROR_MAP: Tag = *b"eMap";
+pub const TILING: Tag = *b"TCFG";
+pub const TILE: Tag = *b"TDAT";
+pub const TILE_INDEX: Tag = *b"tIdx";
+pub const LAYER: Tag = *b"rLyr";
+pub const END: Tag = *b"IEND";
 
-const MAGIC: u32 = 0xBAAD_A555;
+type Chunk = (Tag, Vec<u8>);
+
+// Deflate doesn't compress better than this, bounding the data a chunk inflates to
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+pub fn is_critical(tag: Tag) -> bool {
+    tag[0].is_ascii_uppercase()
+}
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Metadata {
@@ -19,6 +49,718 @@ pub struct Metadata {
     pub width: u32,
     pub height: u32,
     pub scale_level: usize,
+    pub error_map: Option<ErrorMap>,
+    pub tiling: Option<Tiling>,
+}
+
+impl Metadata {
+    /// Number of independently coded tiles, an untiled image is a single tile.
+    pub fn tile_count(&self) -> usize {
+        match self.tiling {
+            Some(tiling) => tiling.count((self.width, self.height)),
+            None => 1,
+        }
+    }
+
+    /// Rectangle of the tile by its position in archive order.
+    pub fn tile(&self, index: usize) -> Rect {
+        tile_rect(self.tiling, (self.width, self.height), index)
+    }
+
+    /// Rectangles of the independently coded tiles in archive order, computed one at a time.
+    pub fn tiles(&self) -> impl Iterator<Item = Rect> {
+        let (tiling, dimensions) = (self.tiling, (self.width, self.height));
+        (0..self.tile_count()).map(move |index| tile_rect(tiling, dimensions, index))
+    }
+
+    // The decoded image is a single buffer, so its size must fit
+    fn check_size(&self) -> Result<(), String> {
+        let size = (self.width as usize).checked_mul(self.height as usize);
+        if size.is_none_or(|size| size > isize::MAX as usize) {
+            return Err(format!("{}x{} image is too large", self.width, self.height));
+        }
+        Ok(())
+    }
+
+    // Tile indices are u32 in the tile index, so their number must fit
+    fn check_tile_count(&self) -> Result<(), String> {
+        if self.tile_count() as u64 > u64::from(u32::MAX) {
+            return Err(format!("{} tiles exceed the limit of {}", self.tile_count(), u32::MAX));
+        }
+        Ok(())
+    }
+
+    /// Maximum difference between a source pixel and its decoded value.
+    pub fn max_error(&self) -> u8 {
+        match self.error_map {
+            Some(ref map) => map.max_error(),
+            None => self.quantization_level.error(),
+        }
+    }
+
+    /// Dimensions of the image decoded up to `level`.
+    pub fn level_dimensions(&self, level: usize) -> (u32, u32) {
+        let factor = 1u32 << self.scale_level.saturating_sub(level);
+        (self.width.div_ceil(factor), self.height.div_ceil(factor))
+    }
+
+    /// Finest level whose image fits into `max_size` on both sides, the first level if none does.
+    pub fn thumbnail_level(&self, max_size: u32) -> usize {
+        (0..=self.scale_level)
+            .rev()
+            .find(|&level| {
+                let (width, height) = self.level_dimensions(level);
+                width <= max_size && height <= max_size
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Quality refinement of the decoded image down to a smaller maximum error.
+/// Layers follow the grid of an untiled archive with decreasing errors, readers unaware of
+/// them decode the base image with the error of the metadata.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Layer {
+    pub max_error: u8,
+    /// Wrapping correction of every pixel in line order
+    pub residues: Vec<u8>,
+}
+
+// Fixed part of Metadata stored in the archive header, laid out as v0 Metadata
+#[derive(Serialize, Deserialize)]
+struct Header {
+    quantization_level: QuantizationLevel,
+    interpolation: InterpolationType,
+    width: u32,
+    height: u32,
+    scale_level: usize,
+}
+
+impl Header {
+    fn into_metadata(self) -> Metadata {
+        Metadata {
+            quantization_level: self.quantization_level,
+            interpolation: self.interpolation,
+            width: self.width,
+            height: self.height,
+            scale_level: self.scale_level,
+            error_map: None,
+            tiling: None,
+        }
+    }
+}
+
+impl<'a> From<&'a Metadata> for Header {
+    fn from(metadata: &'a Metadata) -> Self {
+        Header {
+            quantization_level: metadata.quantization_level,
+            interpolation: metadata.interpolation.clone(),
+            width: metadata.width,
+            height: metadata.height,
+            scale_level: metadata.scale_level,
+        }
+    }
+}
+
+fn checksum(parts: &[&[u8]]) -> u32 {
+    let mut hasher = Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+// Chunk layout: tag, data length, data, CRC32 of tag and data
+pub fn write_chunk<W: Write>(w: &mut W, tag: Tag, data: &[u8]) -> Result<(), Error> {
+    w.write_all(&tag)?;
+    w.write_u32::<LE>(data.len() as u32)?;
+    w.write_all(data)?;
+    w.write_u32::<LE>(checksum(&[&tag, data]))?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(r: &mut R, length: u32) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    r.take(u64::from(length)).read_to_end(&mut data)?;
+    if data.len() != length as usize {
+        return Err(Error::Truncated);
+    }
+    Ok(data)
+}
+
+fn read_chunk_header<R: Read>(r: &mut R) -> Result<(Tag, u32), Error> {
+    let mut tag = [0; 4];
+    r.read_exact(&mut tag).map_err(truncated)?;
+    let length = r.read_u32::<LE>().map_err(truncated)?;
+    Ok((tag, length))
+}
+
+fn read_chunk<R: Read>(r: &mut R) -> Result<(Tag, Vec<u8>), Error> {
+    let (tag, length) = read_chunk_header(r)?;
+    let data = read_bytes(r, length)?;
+    let crc = r.read_u32::<LE>().map_err(truncated)?;
+    if crc != checksum(&[&tag, &data]) {
+        let name = String::from_utf8_lossy(&tag).into_owned();
+        return Err(corrupted(format!("checksum mismatch in {:?} chunk", name)));
+    }
+    Ok((tag, data))
+}
+
+pub(crate) fn write_header<W: Write>(w: &mut W, metadata: &Metadata) -> Result<(), Error> {
+    w.write_u32::<LE>(MAGIC)?;
+    let header = bincode::serialize(&Header::from(metadata))?;
+    let mut prefix = [0; 6];
+    LE::write_u16(&mut prefix[..2], FORMAT_VERSION);
+    LE::write_u32(&mut prefix[2..], header.len() as u32);
+    w.write_all(&prefix)?;
+    w.write_all(&header)?;
+    w.write_u32::<LE>(checksum(&[&prefix, &header]))?;
+
+    if let Some(ref error_map) = metadata.error_map {
+        write_chunk(w, ERROR_MAP, &bincode::serialize(error_map)?)?;
+    }
+    if let Some(ref tiling) = metadata.tiling {
+        write_chunk(w, TILING, &bincode::serialize(tiling)?)?;
+    }
+    Ok(())
+}
+
+// Returns None for archives written before the container format
+fn read_header<R: Read>(r: &mut R) -> Result<Option<Metadata>, Error> {
+    let magic = r.read_u32::<LE>().map_err(truncated)?;
+    if magic == MAGIC_V0 {
+        return Ok(None);
+    }
+    if magic != MAGIC {
+        return Err(Error::BadMagic(magic));
+    };
+
+    let mut prefix = [0; 6];
+    r.read_exact(&mut prefix).map_err(truncated)?;
+    let version = LE::read_u16(&prefix[..2]);
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    // header may have been extended by newer writers, unknown trailing bytes are ignored
+    let header_length = LE::read_u32(&prefix[2..]);
+    let header = read_bytes(r, header_length)?;
+    let crc = r.read_u32::<LE>().map_err(truncated)?;
+    if crc != checksum(&[&prefix, &header]) {
+        return Err(corrupted("header checksum mismatch"));
+    }
+    let header: Header = parse(&header)?;
+    Ok(Some(header.into_metadata()))
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+// Chunk data passed its checksum, running out of it is malformed rather than truncated
+pub(crate) fn parse<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T, Error> {
+    bincode::deserialize(data).map_err(|e| corrupted(e.to_string()))
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut buffer = Vec::new();
+    DeflateDecoder::new(data)
+        .read_to_end(&mut buffer)
+        .map_err(|e| corrupted(format!("bad compressed data: {}", e)))?;
+    Ok(buffer)
+}
+
+// Size of the image downscaled to its first `count` levels
+fn downscaled((width, height): (u32, u32), levels: usize, count: usize) -> (usize, usize) {
+    let factor = 1u32 << (levels + 1 - count);
+    (width.div_ceil(factor) as usize, height.div_ceil(factor) as usize)
+}
+
+const CHUNK_OVERHEAD: usize = 12;
+const INDEX_ENTRY_SIZE: usize = 12;
+
+// Keeps track of the archive offset for the tile index
+struct Counter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn tile_rect(tiling: Option<Tiling>, (width, height): (u32, u32), index: usize) -> Rect {
+    match tiling {
+        Some(tiling) => tiling.tile(index, (width, height)),
+        None => Rect::new(0, 0, width, height),
+    }
+}
+
+/// Writes an archive one tile at a time, an untiled archive is a single tile.
+/// Every tile is stored level by level, an untiled archive holds every level in its own chunk.
+pub struct ArchiveWriter<W: Write> {
+    w: Counter<W>,
+    dimensions: (u32, u32),
+    tiling: Option<Tiling>,
+    tile_count: usize,
+    levels: usize,
+    // chunk offset and data length of every tile
+    index: Vec<(u64, u32)>,
+    // maximum error of the image decoded from the chunks written so far
+    max_error: u8,
+    pixels: usize,
+}
+
+// Metadata the readers would reject or could not decode with
+fn check_metadata(metadata: &Metadata) -> Result<(), Error> {
+    if let Some(ref tiling) = metadata.tiling {
+        tiling.check()?;
+    }
+    metadata.check_size().map_err(invalid_dimensions)?;
+    metadata.check_tile_count().map_err(invalid_dimensions)?;
+    if let Some(ref map) = metadata.error_map {
+        if map.dimensions() != (metadata.width, metadata.height) {
+            return Err(invalid_dimensions("error map dimensions differ from the image"));
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(w: W, metadata: &Metadata) -> Result<Self, Error> {
+        check_metadata(metadata)?;
+        let mut w = Counter { inner: w, written: 0 };
+        write_header(&mut w, metadata)?;
+        Ok(ArchiveWriter {
+            w,
+            dimensions: (metadata.width, metadata.height),
+            tiling: metadata.tiling,
+            tile_count: metadata.tile_count(),
+            levels: metadata.scale_level,
+            index: Vec::new(),
+            max_error: metadata.max_error(),
+            pixels: metadata.width as usize * metadata.height as usize,
+        })
+    }
+
+    pub fn tile_count(&self) -> usize {
+        self.tile_count
+    }
+
+    /// Rectangles of the tiles in the order they are written, computed one at a time.
+    pub fn tiles(&self) -> impl Iterator<Item = Rect> {
+        let (tiling, dimensions) = (self.tiling, self.dimensions);
+        (0..self.tile_count).map(move |index| tile_rect(tiling, dimensions, index))
+    }
+
+    /// Writes the grid of the next tile in line order.
+    pub fn write_tile(&mut self, grid: &Grid) -> Result<(), Error> {
+        if self.index.len() >= self.tile_count {
+            return Err(invalid("all tiles are already written"));
+        }
+        let rect = tile_rect(self.tiling, self.dimensions, self.index.len());
+        if grid.dimensions() != (rect.width as usize, rect.height as usize) {
+            return Err(invalid_dimensions("grid dimensions differ from the tile"));
+        }
+
+        let mut levels = Vec::with_capacity(self.levels + 1);
+        for level in grid.levels(self.levels) {
+            levels.push(deflate(&level)?);
+        }
+
+        if self.tiling.is_some() {
+            let data = bincode::serialize(&levels)?;
+            self.index.push((self.w.written, data.len() as u32));
+            write_chunk(&mut self.w, TILE, &data)
+        } else {
+            self.index.push((self.w.written, 0));
+            for level in &levels {
+                write_chunk(&mut self.w, LEVEL, level)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn check_tiles(&self) -> Result<(), Error> {
+        if self.index.len() != self.tile_count {
+            let (written, tiles) = (self.index.len(), self.tile_count);
+            return Err(invalid(format!("{} of {} tiles written", written, tiles)));
+        }
+        Ok(())
+    }
+
+    /// Writes a refinement layer of an untiled archive after its grid.
+    pub fn write_layer(&mut self, layer: &Layer) -> Result<(), Error> {
+        self.check_tiles()?;
+        if self.tiling.is_some() {
+            return Err(invalid("tiled archives have no quality layers"));
+        }
+        if layer.max_error >= self.max_error {
+            return Err(invalid(format!("layer error {} must be below {}", layer.max_error, self.max_error)));
+        }
+        if layer.residues.len() != self.pixels {
+            return Err(invalid_dimensions("layer size differs from the image"));
+        }
+
+        let data = Layer {
+            max_error: layer.max_error,
+            residues: deflate(&layer.residues)?,
+        };
+        write_chunk(&mut self.w, LAYER, &bincode::serialize(&data)?)?;
+        self.max_error = layer.max_error;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.check_tiles()?;
+
+        // the index is the last chunk before the end, so its offset follows from the tile count,
+        // tiles are addressed by their distance back from the index
+        if self.tiling.is_some() {
+            let mut index = Vec::with_capacity(self.index.len() * INDEX_ENTRY_SIZE);
+            for &(offset, length) in &self.index {
+                index.write_u64::<LE>(self.w.written - offset)?;
+                index.write_u32::<LE>(length)?;
+            }
+            write_chunk(&mut self.w, TILE_INDEX, &index)?;
+        }
+        write_chunk(&mut self.w, END, &[])?;
+        Ok(self.w.inner)
+    }
+}
+
+/// Tile grid along with the last level it holds
+pub type LevelTile = (Rect, Grid, usize);
+
+/// Compressed sizes of the coded data of an archive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PayloadSize {
+    /// Levels or tiles of the grid
+    pub grid: u64,
+    /// Every quality layer in archive order
+    pub layers: Vec<u64>,
+}
+
+/// Reads an archive one tile at a time, an untiled archive is a single tile.
+pub struct ArchiveReader<R: Read> {
+    r: R,
+    metadata: Metadata,
+    tile_count: usize,
+    next: usize,
+    // tile, level or layer chunk read while looking for the metadata chunks
+    pending: Option<Chunk>,
+    // level chunks of the untiled grid not read yet
+    levels_left: usize,
+    // tile chunk positions and data lengths, loaded on the first random access
+    index: Option<Vec<(u64, u32)>>,
+    ended: bool,
+    v0: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut r: R) -> Result<Self, Error> {
+        let (metadata, v0) = match read_header(&mut r)? {
+            Some(metadata) => (metadata, false),
+            None => {
+                let header: Header = bincode::deserialize_from(&mut r)?;
+                (header.into_metadata(), true)
+            }
+        };
+        if metadata.scale_level > MAX_SCALE_LEVEL {
+            return Err(corrupted(format!("too many levels {}", metadata.scale_level)));
+        }
+
+        let mut reader = ArchiveReader {
+            r,
+            tile_count: 0,
+            levels_left: metadata.scale_level + 1,
+            metadata,
+            next: 0,
+            pending: None,
+            index: None,
+            ended: v0,
+            v0,
+        };
+        if !v0 {
+            reader.pending = reader.next_payload()?;
+        }
+        reader.check_metadata()?;
+        reader.tile_count = reader.metadata.tile_count();
+        Ok(reader)
+    }
+
+    // Dimensions are checked before decoders allocate the image for them,
+    // tiles are computed by their index, nothing is allocated for them
+    fn check_metadata(&self) -> Result<(), Error> {
+        self.metadata.check_size().map_err(corrupted)?;
+        self.metadata.check_tile_count().map_err(corrupted)?;
+        if let Some((LEVEL, ref data)) = self.pending {
+            let (width, height) = self.metadata.level_dimensions(0);
+            if u64::from(width) * u64::from(height) / MAX_DEFLATE_RATIO > data.len() as u64 {
+                return Err(corrupted("first level is larger than its chunk can hold"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Whether chunks carry checksums, archives written before the container format have none.
+    pub fn has_checksums(&self) -> bool {
+        !self.v0
+    }
+
+    // Reads chunks up to the next tile, untiled level or layer, None at the end of the archive
+    fn next_payload(&mut self) -> Result<Option<Chunk>, Error> {
+        while !self.ended {
+            let (tag, data) = read_chunk(&mut self.r)?;
+            match tag {
+                END => self.ended = true,
+                LEVEL if self.metadata.tiling.is_none() => return Ok(Some((tag, data))),
+                TILE if self.metadata.tiling.is_some() => return Ok(Some((tag, data))),
+                LAYER => return Ok(Some((tag, data))),
+                ERROR_MAP | LEGACY_ERROR_MAP => {
+                    let (width, height) = (self.metadata.width, self.metadata.height);
+                    self.metadata.error_map = Some(ErrorMap::deserialize(&data, width, height)?);
+                }
+                TILING => {
+                    let tiling: Tiling = parse(&data)?;
+                    tiling.check().map_err(|e| corrupted(e.to_string()))?;
+                    self.metadata.tiling = Some(tiling);
+                }
+                tag if is_critical(tag) => return Err(Error::UnsupportedChunk(tag)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn payload(&mut self) -> Result<Option<Chunk>, Error> {
+        match self.pending.take() {
+            Some(payload) => Ok(Some(payload)),
+            None => self.next_payload(),
+        }
+    }
+
+    // Inflates the first `count` levels of a tile chunk
+    fn tile_levels(&self, data: &[u8], count: usize) -> Result<Vec<Vec<u8>>, Error> {
+        let levels: Vec<Vec<u8>> = parse(data)?;
+        if levels.len() != self.metadata.scale_level + 1 {
+            return Err(corrupted("wrong number of tile levels"));
+        }
+        levels[..count].iter().map(|level| inflate(level)).collect()
+    }
+
+    // With `partial` set a cut short untiled archive yields the levels read before the cut
+    fn read_levels(&mut self, count: usize, partial: bool) -> Result<Vec<Vec<u8>>, Error> {
+        if self.v0 {
+            let grid: Grid = bincode::deserialize_from(DeflateDecoder::new(&mut self.r))?;
+            let mut levels = grid.levels(self.metadata.scale_level);
+            levels.truncate(count);
+            return Ok(levels);
+        }
+
+        if self.metadata.tiling.is_some() {
+            return match self.payload()? {
+                Some((TILE, data)) => self.tile_levels(&data, count),
+                _ => Err(corrupted("missing tiles")),
+            };
+        }
+
+        let mut levels = Vec::with_capacity(count);
+        while levels.len() < count {
+            let level = match self.payload() {
+                Ok(Some((LEVEL, level))) => level,
+                Ok(None) if partial && !levels.is_empty() => break,
+                Ok(_) => return Err(corrupted("missing levels")),
+                Err(Error::Truncated) if partial && !levels.is_empty() => {
+                    self.ended = true;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            self.levels_left -= 1;
+            levels.push(inflate(&level)?);
+        }
+        Ok(levels)
+    }
+
+    // Returns the tile grid with the number of its levels
+    fn next_grid(&mut self, count: usize, partial: bool) -> Result<Option<LevelTile>, Error> {
+        if self.next >= self.tile_count {
+            return Ok(None);
+        }
+        let rect = self.metadata.tile(self.next);
+
+        let levels = self.metadata.scale_level;
+        let data = self.read_levels(count.clamp(1, levels + 1), partial)?;
+        let (width, height) = downscaled((rect.width, rect.height), levels, data.len());
+        let grid = Grid::from_levels(width, height, data.len() - 1, &data)
+            .ok_or_else(|| corrupted("level size mismatch"))?;
+        self.next += 1;
+        Ok(Some((rect, grid, data.len() - 1)))
+    }
+
+    /// Reads the grid of the next tile in line order, None after the last tile.
+    pub fn next_tile(&mut self) -> Result<Option<(Rect, Grid)>, Error> {
+        Ok(self.next_grid(usize::MAX, false)?.map(|(rect, grid, _)| (rect, grid)))
+    }
+
+    /// Reads the next tile restored from its first `level + 1` levels only: the grid of the tile
+    /// downscaled `2^(scale_level - level)` times with `level` levels.
+    pub fn next_tile_to_level(&mut self, level: usize) -> Result<Option<(Rect, Grid)>, Error> {
+        if level > self.metadata.scale_level {
+            return Err(invalid(format!("level {} is beyond {} levels", level, self.metadata.scale_level)));
+        }
+        Ok(self.next_grid(level + 1, false)?.map(|(rect, grid, _)| (rect, grid)))
+    }
+
+    /// Reads the next tile like `next_tile`, but an untiled archive cut short yields the grid
+    /// of the levels complete before the cut along with the last of them.
+    pub fn next_tile_partial(&mut self) -> Result<Option<LevelTile>, Error> {
+        self.next_grid(usize::MAX, true)
+    }
+
+    // Reads the untiled levels left after the last tile
+    fn skip_tiles(&mut self) -> Result<(), Error> {
+        if self.next != self.tile_count {
+            return Err(invalid(format!("{} of {} tiles read", self.next, self.tile_count)));
+        }
+        if self.metadata.tiling.is_none() && !self.v0 {
+            while self.levels_left > 0 {
+                match self.payload()? {
+                    Some((LEVEL, _)) => self.levels_left -= 1,
+                    _ => return Err(corrupted("missing levels")),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next quality layer once every tile is read, None after the last one.
+    pub fn next_layer(&mut self) -> Result<Option<Layer>, Error> {
+        self.skip_tiles()?;
+        let data = match self.payload()? {
+            Some((LAYER, data)) => data,
+            Some(_) => return Err(corrupted("unexpected tiles after the last one")),
+            None => return Ok(None),
+        };
+
+        let layer: Layer = parse(&data)?;
+        let residues = inflate(&layer.residues)?;
+        if residues.len() != self.metadata.width as usize * self.metadata.height as usize {
+            return Err(corrupted("layer size differs from the image"));
+        }
+        Ok(Some(Layer { max_error: layer.max_error, residues }))
+    }
+
+    /// Sizes of the grid and quality layer chunks not read yet, found from the chunk lengths:
+    /// their data is skipped, neither inflated nor checksummed.
+    pub fn payload_size(mut self) -> Result<PayloadSize, Error> {
+        let mut size = PayloadSize::default();
+        if self.v0 {
+            size.grid = io::copy(&mut self.r, &mut io::sink())?;
+            return Ok(size);
+        }
+        let mut chunk = self.pending.take().map(|(tag, data)| (tag, data.len() as u32));
+        loop {
+            match chunk {
+                Some((LAYER, length)) => size.layers.push(u64::from(length)),
+                Some((LEVEL, length)) | Some((TILE, length)) => size.grid += u64::from(length),
+                _ => {}
+            }
+            if self.ended {
+                return Ok(size);
+            }
+
+            let (tag, length) = read_chunk_header(&mut self.r)?;
+            // data and checksum
+            let skip = u64::from(length) + 4;
+            if io::copy(&mut self.r.by_ref().take(skip), &mut io::sink())? != skip {
+                return Err(Error::Truncated);
+            }
+            self.ended = tag == END;
+            chunk = Some((tag, length));
+        }
+    }
+
+    /// Checks that the archive ends after the last tile, skipping quality layers.
+    pub fn finish(mut self) -> Result<Metadata, Error> {
+        self.skip_tiles()?;
+        loop {
+            match self.payload()? {
+                Some((LAYER, _)) => {}
+                Some(_) => return Err(corrupted("unexpected tiles after the last one")),
+                None => return Ok(self.metadata),
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    fn load_index(&mut self) -> Result<Vec<(u64, u32)>, Error> {
+        let index_size = self.tile_count * INDEX_ENTRY_SIZE;
+        let distance = (2 * CHUNK_OVERHEAD + index_size) as i64;
+        let position = self.r.seek(SeekFrom::End(-distance)).map_err(|_| corrupted("missing tile index"))?;
+        let (tag, data) = read_chunk(&mut self.r)?;
+        if tag != TILE_INDEX || data.len() != index_size {
+            return Err(corrupted("missing tile index"));
+        }
+
+        let mut index = Vec::with_capacity(self.tile_count);
+        for mut entry in data.chunks(INDEX_ENTRY_SIZE) {
+            let distance = entry.read_u64::<LE>()?;
+            let length = entry.read_u32::<LE>()?;
+            let offset = position.checked_sub(distance).ok_or_else(|| corrupted("incorrect tile index"))?;
+            index.push((offset, length));
+        }
+        Ok(index)
+    }
+
+    /// Reads the grid of the tile by its position in line order.
+    /// Tiled archives are read through the tile index, an untiled archive only has tile 0
+    /// which must not have been read with `next_tile` before.
+    pub fn read_tile(&mut self, index: usize) -> Result<Grid, Error> {
+        if index >= self.tile_count {
+            return Err(invalid(format!("tile {} is out of {} tiles", index, self.tile_count)));
+        }
+        if self.metadata.tiling.is_none() {
+            return match self.next_tile()? {
+                Some((_, grid)) => Ok(grid),
+                None => Err(invalid("untiled archive grid was already read")),
+            };
+        }
+
+        let entries = match self.index.take() {
+            Some(entries) => entries,
+            None => self.load_index()?,
+        };
+        let (offset, length) = entries[index];
+        self.index = Some(entries);
+        self.r.seek(SeekFrom::Start(offset))?;
+        let (tag, data) = read_chunk(&mut self.r)?;
+        if tag != TILE || data.len() != length as usize {
+            return Err(corrupted("tile index points to a wrong chunk"));
+        }
+
+        let levels = self.metadata.scale_level;
+        let data = self.tile_levels(&data, levels + 1)?;
+        let rect = self.metadata.tile(index);
+        Grid::from_levels(rect.width as usize, rect.height as usize, levels, &data)
+            .ok_or_else(|| corrupted("level size mismatch"))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -27,30 +769,27 @@ pub struct Archive<G> {
     pub grid: G,
 }
 
-impl<G: Serialize + DeserializeOwned> Archive<G> {
-    pub fn serialize_to_writer<W: Write>(&self, mut w: &mut W) -> Result<(), Box<Error>> {
-        w.write_u32::<LE>(MAGIC)?;
-        bincode::serialize_into(&mut w, &self.metadata)?;
-        let mut buffer = Vec::with_capacity(bincode::serialized_size(&self.grid)? as usize);
-        bincode::serialize_into(&mut buffer, &self.grid)?;
-        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(&buffer)?;
-        let compressed_bytes = encoder.finish()?;
-        w.write_all(&compressed_bytes)?;
+impl Archive<Grid> {
+    /// Reads the metadata from the header and the chunks preceding the grid, nothing is inflated.
+    pub fn read_metadata<R: Read>(r: R) -> Result<Metadata, Error> {
+        Ok(ArchiveReader::new(r)?.metadata)
+    }
+
+    pub fn serialize_to_writer<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let mut writer = ArchiveWriter::new(w, &self.metadata)?;
+        writer.write_tile(&self.grid)?;
+        writer.finish()?;
         Ok(())
     }
 
-    pub fn deserialize_from_reader<R: Read>(mut r: &mut R) -> Result<Self, Box<Error>>
-    where
-        Archive<G>: 'static,
-    {
-        let magic = r.read_u32::<LE>()?;
-        if magic != MAGIC {
-            return Err("incorrect magic number".into());
-        };
-        let metadata: Metadata = bincode::deserialize_from(&mut r)?;
-        let decoder = DeflateDecoder::new(r);
-        let grid: G = bincode::deserialize_from(decoder)?;
+    pub fn deserialize_from_reader<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let mut reader = ArchiveReader::new(r)?;
+        if reader.metadata().tiling.is_some() {
+            return Err(invalid("tiled archive must be read with ArchiveReader"));
+        }
+
+        let (_, grid) = reader.next_tile()?.ok_or_else(|| corrupted("missing grid chunk"))?;
+        let metadata = reader.finish()?;
         Ok(Archive { metadata, grid })
     }
 }
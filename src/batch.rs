@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use options::{is_stdio, IO};
+
+/// Extensions of the images taken from input directories.
+pub const IMAGE_EXTENSIONS: &[&str] = &[
+    "bmp", "gif", "hdr", "ico", "jpeg", "jpg", "pbm", "pgm", "png", "pnm", "ppm", "tga", "tif", "tiff", "webp",
+];
+
+/// Extensions of the archives taken from input directories.
+pub const ARCHIVE_EXTENSIONS: &[&str] = &["hgi"];
+
+// Input file with its path relative to the output directory and its output file,
+// an error if an earlier input has the same output
+struct Job {
+    input: PathBuf,
+    relative: PathBuf,
+    output: Result<PathBuf, String>,
+}
+
+// Sizes of the input and output files
+type Outcome = Result<(u64, u64), String>;
+
+/// Several inputs or a directory make the output a directory.
+pub fn is_batch(io: &IO) -> bool {
+    io.input.len() > 1 || io.input.iter().any(|input| input.is_dir())
+}
+
+/// Runs `process` from every input file to its mirror in the output directory with
+/// the `extension`, then prints a summary. Inputs mirrored to the output of an earlier
+/// input are not processed and fail. Fails if any file failed.
+pub fn run<F>(io: &IO, accepted: &[&str], extension: &str, process: F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(&Path, &Path) -> Result<(), Box<dyn Error>> + Sync,
+{
+    if is_stdio(&io.output) {
+        return Err("several files can not be written to stdout".into());
+    }
+    let jobs = collect(io, accepted, extension)?;
+    if jobs.is_empty() {
+        return Err("no input files found".into());
+    }
+
+    let outcomes = map_ordered(&jobs, |job| match job.output {
+        Ok(ref output) => run_job(&job.input, output, &process).map_err(|e| e.to_string()),
+        Err(ref e) => Err(e.clone()),
+    });
+    print_summary(&jobs, &outcomes);
+
+    let failed = outcomes.iter().filter(|outcome| outcome.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} files failed", failed, jobs.len()).into());
+    }
+    Ok(())
+}
+
+fn run_job<F>(input: &Path, output: &Path, process: &F) -> Result<(u64, u64), Box<dyn Error>>
+where
+    F: Fn(&Path, &Path) -> Result<(), Box<dyn Error>>,
+{
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    process(input, output)?;
+    Ok((fs::metadata(input)?.len(), fs::metadata(output)?.len()))
+}
+
+#[cfg(feature = "parallel")]
+fn map_ordered<F>(jobs: &[Job], f: F) -> Vec<Outcome>
+where
+    F: Fn(&Job) -> Outcome + Sync + Send,
+{
+    jobs.par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn map_ordered<F>(jobs: &[Job], f: F) -> Vec<Outcome>
+where
+    F: Fn(&Job) -> Outcome,
+{
+    jobs.iter().map(f).collect()
+}
+
+fn collect(io: &IO, accepted: &[&str], extension: &str) -> Result<Vec<Job>, Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    for input in &io.input {
+        if is_stdio(input) {
+            return Err("stdin can only be the single input".into());
+        } else if input.is_dir() {
+            for path in find(input, io.recursive, accepted)? {
+                let relative = path.strip_prefix(input).expect("found paths are inside the directory").to_path_buf();
+                inputs.push((path, relative));
+            }
+        } else {
+            // named files are taken whatever their extension
+            let name = input.file_name().ok_or_else(|| format!("{} is not a file", input.display()))?;
+            inputs.push((input.clone(), PathBuf::from(name)));
+        }
+    }
+
+    // the first input keeps an output shared by several
+    let mut written: HashMap<PathBuf, usize> = HashMap::new();
+    let mut jobs: Vec<Job> = Vec::with_capacity(inputs.len());
+    for (input, relative) in inputs {
+        let output = io.output.join(&relative).with_extension(extension);
+        let output = match written.get(&output) {
+            Some(&first) => Err(format!("same output as {}", jobs[first].input.display())),
+            None => {
+                written.insert(output.clone(), jobs.len());
+                Ok(output)
+            }
+        };
+        jobs.push(Job { input, relative, output });
+    }
+    Ok(jobs)
+}
+
+/// Files of the directory with one of the `accepted` extensions, in path order.
+pub fn find(dir: &Path, recursive: bool, accepted: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    walk(dir, recursive, accepted, &mut files)?;
+    Ok(files)
+}
+
+fn walk(dir: &Path, recursive: bool, accepted: &[&str], files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            if recursive {
+                walk(&path, recursive, accepted, files)?;
+            }
+        } else if has_extension(&path, accepted) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn has_extension(path: &Path, accepted: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| accepted.iter().any(|accepted| accepted.eq_ignore_ascii_case(extension)))
+}
+
+fn print_summary(jobs: &[Job], outcomes: &[Outcome]) {
+    let names: Vec<String> = jobs.iter().map(|job| job.relative.display().to_string()).collect();
+    let failed = outcomes.iter().filter(|outcome| outcome.is_err()).count();
+    let total = format!("{} files, {} failed", jobs.len(), failed);
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0).max(total.len());
+
+    println!("{:<width$}  {:>12}  {:>12}  {:>7}", "File", "Input", "Output", "Ratio", width = width);
+    let (mut input_total, mut output_total) = (0, 0);
+    for (name, outcome) in names.iter().zip(outcomes) {
+        match *outcome {
+            Ok((input, output)) => {
+                println!("{:<width$}  {:>12}  {:>12}  {:>7.2}", name, input, output, ratio(input, output), width = width);
+                input_total += input;
+                output_total += output;
+            }
+            Err(ref e) => {
+                println!("{:<width$}  failed: {}", name, e, width = width);
+            }
+        }
+    }
+    println!(
+        "{:<width$}  {:>12}  {:>12}  {:>7.2}",
+        total,
+        input_total,
+        output_total,
+        ratio(input_total, output_total),
+        width = width
+    );
+}
+
+fn ratio(input: u64, output: u64) -> f64 {
+    input as f64 / output.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use options::IO;
+    use super::{collect, find, IMAGE_EXTENSIONS};
+
+    // Empty files at the `paths` under a fresh directory
+    fn make_tree(name: &str, paths: &[&str]) -> PathBuf {
+        let root = env::temp_dir().join(name);
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        for path in paths {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        root
+    }
+
+    fn relative(root: &Path, paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|path| path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/")).collect()
+    }
+
+    #[test]
+    fn find_images() {
+        let root = make_tree("hgi_batch_find", &["b.png", "a.jpg", "notes.txt", "sub/c.PNG", "sub/deeper/d.tif"]);
+
+        let files = find(&root, false, IMAGE_EXTENSIONS).unwrap();
+        assert_eq!(relative(&root, &files), vec!["a.jpg", "b.png"]);
+        let files = find(&root, true, IMAGE_EXTENSIONS).unwrap();
+        assert_eq!(relative(&root, &files), vec!["a.jpg", "b.png", "sub/c.PNG", "sub/deeper/d.tif"]);
+        let files = find(&root, true, &["png"]).unwrap();
+        assert_eq!(relative(&root, &files), vec!["b.png", "sub/c.PNG"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn collect_output_collisions() {
+        let root = make_tree("hgi_batch_collect", &["a.jpg", "a.png", "b.png", "sub/a.png"]);
+        let output = root.join("out");
+
+        let io = IO { input: vec![root.clone()], output: output.clone(), recursive: true };
+        let jobs = collect(&io, IMAGE_EXTENSIONS, "hgi").unwrap();
+        let outputs: Vec<_> = jobs.iter().map(|job| job.output.clone()).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                Ok(output.join("a.hgi")),
+                Err(format!("same output as {}", root.join("a.jpg").display())),
+                Ok(output.join("b.hgi")),
+                Ok(output.join("sub").join("a.hgi")),
+            ]
+        );
+
+        // named files from different directories are mirrored by their names alone
+        let io = IO { input: vec![root.join("a.png"), root.join("sub/a.png")], output: output.clone(), recursive: false };
+        let jobs = collect(&io, IMAGE_EXTENSIONS, "hgi").unwrap();
+        assert_eq!(jobs[0].output, Ok(output.join("a.hgi")));
+        assert!(jobs[1].output.is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use hgi::interpolator::InterpolationType;
+use hgi::metrics;
+use hgi::{self, Coding, EncoderConfig};
+use image::jpeg::JPEGEncoder;
+use image::png::PNGEncoder;
+use image::{ColorType, DynamicImage, GrayImage};
+use serde_json;
+
+use batch;
+
+/// Settings swept over every image of the corpus.
+pub struct Matrix {
+    pub errors: Vec<u8>,
+    pub levels: Vec<usize>,
+    pub interpolations: Vec<InterpolationType>,
+    pub codings: Vec<Coding>,
+}
+
+impl Matrix {
+    fn configs(&self) -> Vec<EncoderConfig> {
+        let mut configs = Vec::new();
+        for &max_error in &self.errors {
+            for &levels in &self.levels {
+                for interpolation in &self.interpolations {
+                    for coding in &self.codings {
+                        configs.push(
+                            EncoderConfig::default()
+                                .max_error(max_error)
+                                .levels(levels)
+                                .interpolation(interpolation.clone())
+                                .coding(coding.clone()),
+                        );
+                    }
+                }
+            }
+        }
+        configs
+    }
+}
+
+// Result of coding one image with one config
+#[derive(Serialize)]
+struct Measurement {
+    file: String,
+    width: u32,
+    height: u32,
+    max_error: u8,
+    levels: usize,
+    interpolation: String,
+    coding: String,
+    size: usize,
+    bits_per_pixel: f64,
+    psnr: f64,
+    measured_max_error: u8,
+    encode_ms: f64,
+    decode_ms: f64,
+}
+
+const CSV_HEADER: &str = "file,width,height,max_error,levels,interpolation,coding,size,bits_per_pixel,psnr,\
+                          measured_max_error,encode_ms,decode_ms";
+
+fn coding_name(coding: &Coding) -> String {
+    match *coding {
+        Coding::Progressive => "progressive".to_string(),
+        Coding::Tiled(size) => format!("tiled:{}", size),
+        Coding::Layered(ref errors) => {
+            let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            format!("layered:{}", errors.join("/"))
+        }
+    }
+}
+
+/// Codes every image of `dir` with every config of the matrix, writes a CSV or JSON
+/// report of the measurements to `w`. Fails after the report if any run failed.
+pub fn run<W: Write>(dir: &Path, recursive: bool, matrix: &Matrix, json: bool, mut w: W) -> Result<(), Box<dyn Error>> {
+    let files = batch::find(dir, recursive, batch::IMAGE_EXTENSIONS)?;
+    if files.is_empty() {
+        return Err(format!("no images found in {}", dir.display()).into());
+    }
+    let configs = matrix.configs();
+    if configs.is_empty() {
+        return Err("the settings matrix is empty".into());
+    }
+
+    let (mut measurements, mut failed) = (Vec::new(), 0);
+    for path in &files {
+        let name = path.strip_prefix(dir).unwrap_or(path).display().to_string();
+        let image = match hgi::open(path) {
+            Ok(image) => image.to_luma(),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                failed += configs.len();
+                continue;
+            }
+        };
+        eprintln!("{}: {} settings", name, configs.len());
+        for config in &configs {
+            match measure(&name, &image, config) {
+                Ok(measurement) => measurements.push(measurement),
+                Err(e) => {
+                    eprintln!(
+                        "{} with max error {}, {} levels, {}, {}: {}",
+                        name,
+                        config.max_error,
+                        config.levels,
+                        config.interpolation,
+                        coding_name(&config.coding),
+                        e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if json {
+        serde_json::to_writer_pretty(&mut w, &measurements)?;
+        writeln!(w)?;
+    } else {
+        write_csv(&mut w, &measurements)?;
+    }
+    w.flush()?;
+
+    if failed > 0 {
+        return Err(format!("{} of {} runs failed", failed, files.len() * configs.len()).into());
+    }
+    Ok(())
+}
+
+fn measure(name: &str, image: &GrayImage, config: &EncoderConfig) -> Result<Measurement, Box<dyn Error>> {
+    let source = DynamicImage::ImageLuma8(image.clone());
+    let start = Instant::now();
+    let archive = config.encode_to_writer(&source, Vec::new())?;
+    let encode_time = start.elapsed();
+
+    let start = Instant::now();
+    let decoded = hgi::decode_from_reader(&archive[..])?.to_luma();
+    let decode_time = start.elapsed();
+
+    let (width, height) = image.dimensions();
+    Ok(Measurement {
+        file: name.to_string(),
+        width,
+        height,
+        max_error: config.max_error,
+        levels: config.levels,
+        interpolation: config.interpolation.to_string(),
+        coding: coding_name(&config.coding),
+        size: archive.len(),
+        bits_per_pixel: archive.len() as f64 * 8.0 / (f64::from(width) * f64::from(height)),
+        psnr: metrics::psnr(image, &decoded)?,
+        measured_max_error: metrics::max_error(image, &decoded)?,
+        encode_ms: encode_time.as_secs_f64() * 1000.0,
+        decode_ms: decode_time.as_secs_f64() * 1000.0,
+    })
+}
+
+fn write_csv<W: Write>(w: &mut W, measurements: &[Measurement]) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "{}", CSV_HEADER)?;
+    for m in measurements {
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{},{},{:.4},{:.4},{},{:.3},{:.3}",
+            csv_field(&m.file),
+            m.width,
+            m.height,
+            m.max_error,
+            m.levels,
+            m.interpolation,
+            m.coding,
+            m.size,
+            m.bits_per_pixel,
+            m.psnr,
+            m.measured_max_error,
+            m.encode_ms,
+            m.decode_ms
+        )?;
+    }
+    Ok(())
+}
+
+// Quotes fields holding separators, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Point of a rate-distortion curve
+struct RatePoint {
+    codec: &'static str,
+    setting: String,
+    size: usize,
+    bits_per_pixel: f64,
+    psnr: f64,
+    max_error: u8,
+}
+
+impl RatePoint {
+    fn new(codec: &'static str, setting: String, image: &GrayImage, encoded: &[u8], decoded: &GrayImage) -> Result<Self, Box<dyn Error>> {
+        Ok(RatePoint {
+            codec,
+            setting,
+            size: encoded.len(),
+            bits_per_pixel: encoded.len() as f64 * 8.0 / image.len() as f64,
+            psnr: metrics::psnr(image, decoded)?,
+            max_error: metrics::max_error(image, decoded)?,
+        })
+    }
+}
+
+/// Writes bits per pixel, PSNR and maximum error of the image coded by HGI at every maximum
+/// error, by PNG and by JPEG at every quality as CSV rows, one series per codec.
+pub fn rate_distortion<W: Write>(
+    image: &GrayImage,
+    levels: usize,
+    errors: &[u8],
+    qualities: &[u8],
+    mut w: W,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(quality) = qualities.iter().find(|&&quality| quality == 0 || quality > 100) {
+        return Err(format!("JPEG quality {} is not between 1 and 100", quality).into());
+    }
+    let (width, height) = image.dimensions();
+    let source = DynamicImage::ImageLuma8(image.clone());
+
+    let mut points = Vec::new();
+    for &max_error in errors {
+        let config = EncoderConfig::default().max_error(max_error).levels(levels);
+        let encoded = config.encode_to_writer(&source, Vec::new())?;
+        let decoded = hgi::decode_from_reader(&encoded[..])?.to_luma();
+        points.push(RatePoint::new("hgi", max_error.to_string(), image, &encoded, &decoded)?);
+    }
+
+    let mut encoded = Vec::new();
+    PNGEncoder::new(&mut encoded).encode(image, width, height, ColorType::Gray(8))?;
+    let decoded = image::load_from_memory(&encoded)?.to_luma();
+    points.push(RatePoint::new("png", "lossless".to_string(), image, &encoded, &decoded)?);
+
+    for &quality in qualities {
+        let mut encoded = Vec::new();
+        JPEGEncoder::new_with_quality(&mut encoded, quality).encode(image, width, height, ColorType::Gray(8))?;
+        let decoded = image::load_from_memory(&encoded)?.to_luma();
+        points.push(RatePoint::new("jpeg", quality.to_string(), image, &encoded, &decoded)?);
+    }
+
+    writeln!(w, "codec,setting,size,bits_per_pixel,psnr,max_error")?;
+    for p in &points {
+        writeln!(
+            w,
+            "{},{},{},{:.4},{:.4},{}",
+            p.codec, p.setting, p.size, p.bits_per_pixel, p.psnr, p.max_error
+        )?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::{csv_field, rate_distortion, write_csv, Measurement, CSV_HEADER};
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("plain/name.png"), "plain/name.png");
+        assert_eq!(csv_field("a,b.png"), "\"a,b.png\"");
+        assert_eq!(csv_field("say \"cheese\".png"), "\"say \"\"cheese\"\".png\"");
+        assert_eq!(csv_field("two\nlines.png"), "\"two\nlines.png\"");
+        assert_eq!(csv_field("carriage\r.png"), "\"carriage\r.png\"");
+    }
+
+    #[test]
+    fn csv_rows() {
+        let measurement = Measurement {
+            file: "dir/a,b.png".to_string(),
+            width: 4,
+            height: 2,
+            max_error: 3,
+            levels: 2,
+            interpolation: "Crossed".to_string(),
+            coding: "layered:2/0".to_string(),
+            size: 40,
+            bits_per_pixel: 40.0,
+            psnr: 45.25,
+            measured_max_error: 3,
+            encode_ms: 1.5,
+            decode_ms: 0.25,
+        };
+        let mut buffer = Vec::new();
+        write_csv(&mut buffer, &[measurement]).unwrap();
+
+        let csv = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, vec![
+            CSV_HEADER,
+            "\"dir/a,b.png\",4,2,3,2,Crossed,layered:2/0,40,40.0000,45.2500,3,1.500,0.250",
+        ]);
+    }
+
+    #[test]
+    fn rate_distortion_rows() {
+        let mut image = GrayImage::new(32, 24);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Luma([(x * 5 + y * 3 + (x * y) % 7) as u8]);
+        }
+        let mut buffer = Vec::new();
+        rate_distortion(&image, 2, &[0, 4, 10], &[50, 90], &mut buffer).unwrap();
+
+        let csv = String::from_utf8(buffer).unwrap();
+        let rows: Vec<Vec<&str>> = csv.lines().skip(1).map(|line| line.split(',').collect()).collect();
+        let series = |codec: &str| rows.iter().filter(|row| row[0] == codec).cloned().collect::<Vec<_>>();
+        assert_eq!(csv.lines().next(), Some("codec,setting,size,bits_per_pixel,psnr,max_error"));
+
+        let hgi = series("hgi");
+        let settings: Vec<&str> = hgi.iter().map(|row| row[1]).collect();
+        assert_eq!(settings, vec!["0", "4", "10"]);
+        for row in &hgi {
+            assert!(row[5].parse::<u8>().unwrap() <= row[1].parse().unwrap());
+        }
+
+        let png = series("png");
+        assert_eq!(png.len(), 1);
+        assert_eq!((png[0][1], png[0][4], png[0][5]), ("lossless", "inf", "0"));
+        assert_eq!(series("jpeg").len(), 2);
+
+        for &quality in &[0, 101] {
+            assert!(rate_distortion(&image, 2, &[0], &[quality], Vec::new()).is_err());
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LE};
+use image::{self, ColorType, DecodingResult, DynamicImage, GrayImage, ImageDecoder, ImageError, ImageFormat, ImageResult};
+
+use archive::{ArchiveReader, Metadata, MAGIC, MAGIC_V0};
+use decoder::{decode_archive, decode_from_reader};
+use encoder::EncoderConfig;
+use error::{invalid_dimensions, Error};
+
+impl From<Error> for ImageError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => ImageError::IoError(e),
+            Error::UnsupportedColorType(color) => ImageError::UnsupportedColor(color),
+            Error::InvalidDimensions(_) => ImageError::DimensionError,
+            e @ Error::UnsupportedVersion(_)
+            | e @ Error::UnsupportedChunk(_)
+            | e @ Error::UnsupportedInterpolation(_)
+            | e @ Error::Unsupported(_) => ImageError::UnsupportedError(e.to_string()),
+            e => ImageError::FormatError(e.to_string()),
+        }
+    }
+}
+
+/// Decoder of the `image` crate for archives. Dimensions and colour type come from the header,
+/// the residues are only decoded on the first read.
+pub struct HgiDecoder<R: Read> {
+    reader: ArchiveReader<R>,
+    image: Option<GrayImage>,
+    // rows returned by `read_scanline`
+    rows: u32,
+}
+
+impl<R: Read> HgiDecoder<R> {
+    pub fn new(r: R) -> Result<Self, Error> {
+        Ok(HgiDecoder {
+            reader: ArchiveReader::new(r)?,
+            image: None,
+            rows: 0,
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        self.reader.metadata()
+    }
+
+    fn decoded(&mut self) -> Result<&GrayImage, Error> {
+        if self.image.is_none() {
+            self.image = Some(decode_archive(&mut self.reader)?.0);
+        }
+        Ok(self.image.as_ref().expect("image is decoded"))
+    }
+}
+
+impl<R: Read> ImageDecoder for HgiDecoder<R> {
+    fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
+        let metadata = self.reader.metadata();
+        Ok((metadata.width, metadata.height))
+    }
+
+    fn colortype(&mut self) -> ImageResult<ColorType> {
+        Ok(ColorType::Gray(8))
+    }
+
+    fn row_len(&mut self) -> ImageResult<usize> {
+        Ok(self.reader.metadata().width as usize)
+    }
+
+    fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
+        let row = self.rows;
+        let image = self.decoded()?;
+        if row >= image.height() {
+            return Err(ImageError::ImageEnd);
+        }
+        let width = image.width() as usize;
+        buf[..width].copy_from_slice(&image.as_ref()[row as usize * width..][..width]);
+        self.rows += 1;
+        Ok(self.rows)
+    }
+
+    fn read_image(&mut self) -> ImageResult<DecodingResult> {
+        self.decoded()?;
+        let image = self.image.take().expect("image is decoded");
+        Ok(DecodingResult::U8(image.into_raw()))
+    }
+}
+
+/// Encoder shaped like the encoders of the `image` crate, writing an archive with the given config.
+pub struct HgiEncoder<W: Write> {
+    w: W,
+    config: EncoderConfig,
+}
+
+impl<W: Write> HgiEncoder<W> {
+    pub fn new(w: W) -> Self {
+        HgiEncoder::with_config(w, EncoderConfig::default())
+    }
+
+    pub fn with_config(w: W, config: EncoderConfig) -> Self {
+        HgiEncoder { w, config }
+    }
+
+    /// Encodes `data` of a `width x height` image, only 8 bit grayscale is supported.
+    pub fn encode(self, data: &[u8], width: u32, height: u32, color: ColorType) -> Result<(), Error> {
+        if color != ColorType::Gray(8) {
+            return Err(Error::UnsupportedColorType(color));
+        }
+        let image = GrayImage::from_raw(width, height, data.to_vec())
+            .ok_or_else(|| invalid_dimensions("data is shorter than the image"))?;
+        self.config.encode_to_writer(&DynamicImage::ImageLuma8(image), self.w)?;
+        Ok(())
+    }
+}
+
+/// Opens an image like `image::open`, archives are recognized by their magic number.
+pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<DynamicImage> {
+    let mut file = BufReader::new(File::open(path.as_ref())?);
+    if is_archive(file.fill_buf()?) {
+        return Ok(decode_from_reader(file)?);
+    }
+    image::open(path)
+}
+
+/// Loads an image like `image::load_from_memory`, archives are recognized by their magic number.
+pub fn load_from_memory(buffer: &[u8]) -> ImageResult<DynamicImage> {
+    if is_archive(buffer) {
+        return Ok(decode_from_reader(buffer)?);
+    }
+    // the format guess of `image` misses the TIFF signatures
+    if buffer.starts_with(b"II*\0") || buffer.starts_with(b"MM\0*") {
+        return image::load_from_memory_with_format(buffer, ImageFormat::TIFF);
+    }
+    image::load_from_memory(buffer)
+}
+
+fn is_archive(start: &[u8]) -> bool {
+    start.len() >= 4 && [MAGIC, MAGIC_V0].contains(&LE::read_u32(start))
+}
@@ -1,7 +1,12 @@
+use std::io::{Read, Seek};
+
+use archive::{ArchiveReader, Metadata, MAX_SCALE_LEVEL};
+use error::{corrupted, invalid, invalid_dimensions, Error};
 use grid::Grid;
-use image::{GenericImage, GrayImage};
-use interpolator::Interpolator;
-use utils::{gray, traverse_level};
+use image::{DynamicImage, GenericImage, GrayImage};
+use interpolator::{Crossed, InterpolationType, Interpolator, LeftTop, Segment};
+use tiling::Rect;
+use utils::{batch_size, crop, gray, map_ordered, segment_pixels_mut, traverse_segments_with};
 
 pub struct Decoder<I> {
     interpolator: I,
@@ -15,33 +20,337 @@ where
         Decoder { interpolator }
     }
 
-    pub fn decode(&mut self, (width, height): (u32, u32), levels: usize, grid: &Grid) -> GrayImage {
+    pub fn decode(&mut self, dimensions: (u32, u32), levels: usize, grid: &Grid) -> Result<GrayImage, Error> {
+        check_grid(dimensions, levels, grid)?;
+        Ok(self.decode_grid(dimensions, levels, grid))
+    }
+
+    fn decode_grid(&self, (width, height): (u32, u32), levels: usize, grid: &Grid) -> GrayImage {
+        let mut image = GrayImage::new(width, height);
+        self.decode_area(levels, grid, (0, width, 0, height), &mut image);
+        image
+    }
+
+    // Decodes the tiles a thread per tile, keeping their order
+    fn decode_batch(&self, levels: usize, tiles: Vec<(Rect, Grid)>) -> Vec<(Rect, GrayImage)> {
+        map_ordered(tiles, |(rect, grid)| {
+            let (width, height) = grid.dimensions();
+            (rect, self.decode_grid((width as u32, height as u32), levels, &grid))
+        })
+    }
+
+    /// Decodes `rect` of the grid restoring only the nodes it depends on.
+    pub fn decode_rect(
+        &mut self,
+        (width, height): (u32, u32),
+        levels: usize,
+        grid: &Grid,
+        rect: Rect,
+    ) -> Result<GrayImage, Error> {
+        check_grid((width, height), levels, grid)?;
+        if rect.intersection(&Rect::new(0, 0, width, height)) != Some(rect) {
+            return Err(invalid_dimensions("region is out of the image"));
+        }
+        // predictions only look right and down inside the enclosing first level cell, and every
+        // node they use does the same on a coarser level, so the chain ends within two cells
+        let step = 1u32 << levels;
+        let reach = |end: u32, size: u32| (((end + step - 1) & !(step - 1)) + 2 * step).min(size);
+        let x1 = rect.x & !(step - 1);
+        let y1 = rect.y & !(step - 1);
+        let x2 = reach(rect.x + rect.width, width);
+        let y2 = reach(rect.y + rect.height, height);
+
         let mut image = GrayImage::new(width, height);
+        self.decode_area(levels, grid, (x1, x2, y1, y2), &mut image);
+        Ok(crop(&image, rect))
+    }
 
+    // `x1` and `y1` must be aligned to the first level step
+    fn decode_area(&self, levels: usize, grid: &Grid, (x1, x2, y1, y2): (u32, u32, u32, u32), image: &mut GrayImage) {
         // initialize first level
         let step = 1 << levels;
-        for line in (0..height).step_by(step) {
-            for column in (0..width).step_by(step) {
-                let value = unsafe { grid.get(column, line) };
+        for line in (y1..y2).step_by(step) {
+            for column in (x1..x2).step_by(step) {
+                let value = unsafe { grid.get_unchecked(column, line) };
                 unsafe { image.unsafe_put_pixel(column, line, gray(value)) };
             }
         }
 
         for level in 0..levels {
-            let process_pixel = #[inline(always)]
-            |column, line| {
-                let diff = unsafe { grid.get(column, line) };
+            let predict_segment = #[inline(always)]
+            |segment: Segment, image: &GrayImage, buffer: &mut Vec<u8>| {
+                let start = buffer.len();
+                buffer.resize(start + segment.count as usize, 0);
+                let values = &mut buffer[start..];
+                self.interpolator
+                    .interpolate_segment(levels, level + 1, segment, image, values);
 
-                let prediction =
-                    self.interpolator
-                        .interpolate(levels, level + 1, (column, line), &image);
+                for (value, column) in values.iter_mut().zip(segment.columns()) {
+                    let diff = unsafe { grid.get_unchecked(column, segment.line) };
+                    *value = value.wrapping_add(diff);
+                }
+            };
 
-                let pixel = gray(prediction.wrapping_add(diff));
-                unsafe { image.unsafe_put_pixel(column, line, pixel) };
+            let store_segment = #[inline(always)]
+            |segment: Segment, values: &[u8], image: &mut GrayImage| {
+                for (pixel, &value) in segment_pixels_mut(image, segment).zip(values) {
+                    *pixel = value;
+                }
             };
 
-            traverse_level(level, levels, 0, width, 0, height, process_pixel);
+            traverse_segments_with(level, levels, (x1, x2, y1, y2), image, predict_segment, store_segment);
         }
-        image
+    }
+
+    /// Decodes the remaining tiles of the archive into the whole image.
+    pub fn decode_tiles<R: Read>(&mut self, reader: &mut ArchiveReader<R>) -> Result<GrayImage, Error> {
+        let (width, height, levels) = {
+            let metadata = reader.metadata();
+            (metadata.width, metadata.height, metadata.scale_level)
+        };
+
+        let mut image = GrayImage::new(width, height);
+        loop {
+            let batch = read_batch(|| reader.next_tile())?;
+            if batch.is_empty() {
+                return Ok(image);
+            }
+            for (rect, tile) in self.decode_batch(levels, batch) {
+                image.copy_from(&tile, rect.x, rect.y);
+            }
+        }
+    }
+
+    /// Decodes the whole image refined by every quality layer the archive holds.
+    /// An archive cut short ends the refinement, the result is returned with its maximum error.
+    /// Corrupted layers are errors.
+    pub fn decode_layers<R: Read>(&mut self, reader: &mut ArchiveReader<R>) -> Result<(GrayImage, u8), Error> {
+        let (image, max_error, _) = self.refine(reader, false)?;
+        Ok((image, max_error))
+    }
+
+    /// Decodes the whole image like `decode_layers`, but an archive cut short is an error too.
+    /// Returns the number of layers applied along with the image and its maximum error.
+    pub fn decode_layers_strict<R: Read>(&mut self, reader: &mut ArchiveReader<R>) -> Result<(GrayImage, u8, usize), Error> {
+        self.refine(reader, true)
+    }
+
+    fn refine<R: Read>(&mut self, reader: &mut ArchiveReader<R>, strict: bool) -> Result<(GrayImage, u8, usize), Error> {
+        let mut image = self.decode_tiles(reader)?;
+        let (mut max_error, mut layers) = (reader.metadata().max_error(), 0);
+        loop {
+            let layer = match reader.next_layer() {
+                Ok(Some(layer)) => layer,
+                Ok(None) => break,
+                Err(Error::Truncated) if !strict => break,
+                Err(e) => return Err(e),
+            };
+            for (pixel, residue) in image.pixels_mut().zip(layer.residues) {
+                pixel.data[0] = pixel.data[0].wrapping_add(residue);
+            }
+            max_error = layer.max_error;
+            layers += 1;
+        }
+        Ok((image, max_error, layers))
+    }
+
+    /// Decodes the remaining tiles of the archive up to `level`, reading only the levels
+    /// it needs. The image is downscaled `2^(scale_level - level)` times.
+    pub fn decode_to_level<R: Read>(&mut self, reader: &mut ArchiveReader<R>, level: usize) -> Result<GrayImage, Error> {
+        let levels = reader.metadata().scale_level;
+        if level > levels {
+            return Err(invalid(format!("level {} is beyond {} levels", level, levels)));
+        }
+
+        let factor = 1u32 << (levels - level);
+        let (width, height) = reader.metadata().level_dimensions(level);
+        let mut image = GrayImage::new(width, height);
+        loop {
+            let batch = read_batch(|| reader.next_tile_to_level(level))?;
+            if batch.is_empty() {
+                return Ok(image);
+            }
+            for (rect, tile) in self.decode_batch(level, batch) {
+                place(&mut image, &tile, rect, factor);
+            }
+        }
+    }
+
+    /// Decodes whatever levels an untiled archive holds, possibly cut short, into
+    /// an image downscaled accordingly. Returns the image with the last level decoded.
+    pub fn decode_preview<R: Read>(&mut self, reader: &mut ArchiveReader<R>) -> Result<(GrayImage, usize), Error> {
+        if reader.metadata().tiling.is_some() {
+            let level = reader.metadata().scale_level;
+            return Ok((self.decode_tiles(reader)?, level));
+        }
+
+        let (_, grid, level) = reader.next_tile_partial()?.ok_or_else(|| corrupted("missing grid"))?;
+        let (width, height) = grid.dimensions();
+        Ok((self.decode_grid((width as u32, height as u32), level, &grid), level))
+    }
+
+    /// Decodes `rect` of the image reading only the tiles it overlaps.
+    /// Untiled archives are unsupported: the whole grid would be read for any region.
+    pub fn decode_region<R: Read + Seek>(
+        &mut self,
+        reader: &mut ArchiveReader<R>,
+        rect: Rect,
+    ) -> Result<GrayImage, Error> {
+        let (width, height, levels) = {
+            let metadata = reader.metadata();
+            (metadata.width, metadata.height, metadata.scale_level)
+        };
+        if reader.metadata().tiling.is_none() {
+            return Err(Error::Unsupported("region decoding needs a tiled archive".to_string()));
+        }
+        if rect.intersection(&Rect::new(0, 0, width, height)) != Some(rect) {
+            return Err(invalid_dimensions("region is out of the image"));
+        }
+
+        let mut image = GrayImage::new(rect.width, rect.height);
+        let tiles = reader.metadata().tiles();
+        for (index, tile) in tiles.enumerate() {
+            if let Some(part) = tile.intersection(&rect) {
+                let grid = reader.read_tile(index)?;
+                let local = Rect::new(part.x - tile.x, part.y - tile.y, part.width, part.height);
+                let decoded = self.decode_rect((tile.width, tile.height), levels, &grid, local)?;
+                image.copy_from(&decoded, part.x - rect.x, part.y - rect.y);
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Decodes a whole archive written by `EncoderConfig::encode_to_writer` or the CLI,
+/// refined by every quality layer it holds.
+pub fn decode_from_reader<R: Read>(r: R) -> Result<DynamicImage, Error> {
+    let mut reader = ArchiveReader::new(r)?;
+    let (image, _) = decode_archive(&mut reader)?;
+    Ok(DynamicImage::ImageLuma8(image))
+}
+
+/// Decodes the rest of the archive with the interpolator it was written with, refined by every
+/// quality layer it holds. Returns the image with the maximum error of the last layer applied.
+pub fn decode_archive<R: Read>(reader: &mut ArchiveReader<R>) -> Result<(GrayImage, u8), Error> {
+    let (image, max_error, _) = refine_archive(reader, false)?;
+    Ok((image, max_error))
+}
+
+/// Decodes the rest of the archive up to `level` with the interpolator it was written with.
+pub fn decode_archive_to_level<R: Read>(reader: &mut ArchiveReader<R>, level: usize) -> Result<GrayImage, Error> {
+    match reader.metadata().interpolation {
+        InterpolationType::Crossed => Decoder::new(Crossed).decode_to_level(reader, level),
+        InterpolationType::Previous => Decoder::new(LeftTop).decode_to_level(reader, level),
+        ref interpolation => Err(Error::UnsupportedInterpolation(interpolation.clone())),
+    }
+}
+
+/// Decodes the rest of the archive with the interpolator it was written with, failing on any
+/// damaged, missing or cut short chunk. Returns the image with the maximum error of the last
+/// layer applied and the number of layers.
+pub fn decode_archive_strict<R: Read>(reader: &mut ArchiveReader<R>) -> Result<(GrayImage, u8, usize), Error> {
+    refine_archive(reader, true)
+}
+
+fn refine_archive<R: Read>(reader: &mut ArchiveReader<R>, strict: bool) -> Result<(GrayImage, u8, usize), Error> {
+    match reader.metadata().interpolation {
+        InterpolationType::Crossed => Decoder::new(Crossed).refine(reader, strict),
+        InterpolationType::Previous => Decoder::new(LeftTop).refine(reader, strict),
+        ref interpolation => Err(Error::UnsupportedInterpolation(interpolation.clone())),
+    }
+}
+
+// The decoder reads the grid unchecked, so it must cover the whole image
+fn check_grid((width, height): (u32, u32), levels: usize, grid: &Grid) -> Result<(), Error> {
+    if levels > MAX_SCALE_LEVEL {
+        return Err(invalid(format!("scale level must not exceed {}", MAX_SCALE_LEVEL)));
+    }
+    if grid.dimensions() != (width as usize, height as usize) {
+        return Err(invalid_dimensions("grid doesn't match the image dimensions"));
+    }
+    Ok(())
+}
+
+// Reads up to a tile per thread
+fn read_batch<F>(mut next_tile: F) -> Result<Vec<(Rect, Grid)>, Error>
+where
+    F: FnMut() -> Result<Option<(Rect, Grid)>, Error>,
+{
+    let mut batch = Vec::new();
+    while batch.len() < batch_size() {
+        match next_tile()? {
+            Some(tile) => batch.push(tile),
+            None => break,
+        }
+    }
+    Ok(batch)
+}
+
+// Copies a tile downscaled `factor` times into the downscaled image: every image pixel
+// takes the nearest tile node at or before it
+fn place(image: &mut GrayImage, tile: &GrayImage, rect: Rect, factor: u32) {
+    let (tile_width, tile_height) = tile.dimensions();
+    for y in rect.y.div_ceil(factor)..(rect.y + rect.height).div_ceil(factor) {
+        let line = ((y * factor - rect.y) / factor).min(tile_height - 1);
+        for x in rect.x.div_ceil(factor)..(rect.x + rect.width).div_ceil(factor) {
+            let column = ((x * factor - rect.x) / factor).min(tile_width - 1);
+            image.put_pixel(x, y, *tile.get_pixel(column, line));
+        }
+    }
+}
+
+/// Decodes an archive band by band, a band is a line of tiles spanning the image width.
+/// Only the tiles of the current band are held in memory, an untiled archive is a single band.
+pub struct StreamDecoder<I, R: Read> {
+    decoder: Decoder<I>,
+    reader: ArchiveReader<R>,
+}
+
+impl<I, R> StreamDecoder<I, R>
+where
+    I: Interpolator,
+    R: Read,
+{
+    pub fn new(decoder: Decoder<I>, r: R) -> Result<Self, Error> {
+        Ok(StreamDecoder {
+            decoder,
+            reader: ArchiveReader::new(r)?,
+        })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        self.reader.metadata()
+    }
+
+    /// Decodes the next band, returns its first line and pixels. None after the last band.
+    pub fn next_band(&mut self) -> Result<Option<(u32, GrayImage)>, Error> {
+        let levels = self.reader.metadata().scale_level;
+        let width = self.reader.metadata().width;
+
+        let mut tiles = Vec::new();
+        loop {
+            match self.reader.next_tile()? {
+                Some((rect, grid)) => {
+                    tiles.push((rect, grid));
+                    if rect.x + rect.width == width {
+                        break;
+                    }
+                }
+                None if tiles.is_empty() => return Ok(None),
+                None => return Err(corrupted("archive ends inside a band")),
+            }
+        }
+
+        let (line, height) = (tiles[0].0.y, tiles[0].0.height);
+        let mut band = GrayImage::new(width, height);
+        for (rect, tile) in self.decoder.decode_batch(levels, tiles) {
+            band.copy_from(&tile, rect.x, 0);
+        }
+        Ok(Some((line, band)))
+    }
+
+    /// Checks that the archive ends after the last band.
+    pub fn finish(self) -> Result<Metadata, Error> {
+        self.reader.finish()
     }
 }
@@ -1,8 +1,14 @@
+use std::io::Write;
+
+use archive::{ArchiveWriter, Layer, Metadata, MAX_SCALE_LEVEL};
+use error::{invalid, invalid_dimensions, Error};
+use error_map::ErrorMap;
 use grid::Grid;
-use image::{GenericImage, GrayImage};
-use interpolator::Interpolator;
-use quantizator::Quantizator;
-use utils::{gray, traverse_level};
+use image::{DynamicImage, GenericImage, GrayImage};
+use interpolator::{Crossed, InterpolationType, Interpolator, LeftTop, Segment};
+use quantizator::{Linear, QuantizationLevel, Quantizator, MAX_ERROR};
+use tiling::{Rect, Tiling};
+use utils::{batch_size, crop, map_ordered, segment_pixels, segment_pixels_mut, traverse_segments_with};
 
 pub struct Encoder<I, Q> {
     interpolator: I,
@@ -31,42 +37,341 @@ where
         for line in (0..height).step_by(step) {
             for column in (0..width).step_by(step) {
                 let pixel = unsafe { image.unsafe_get_pixel(column, line).data[0] };
-                unsafe { grid.set((column, line), pixel) };
+                unsafe { grid.set_unchecked((column, line), pixel) };
             }
         }
     }
 
-    pub fn encode(&mut self, mut input: GrayImage) -> Grid {
+    fn check_scale_level(&self) -> Result<(), Error> {
+        if self.scale_level > MAX_SCALE_LEVEL {
+            return Err(invalid(format!("scale level must not exceed {}", MAX_SCALE_LEVEL)));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&mut self, input: GrayImage) -> Result<Grid, Error> {
+        self.encode_tile(input, (0, 0))
+    }
+
+    /// Encodes a tile of the image, `origin` is the position of its top left pixel.
+    pub fn encode_tile(&mut self, input: GrayImage, origin: (u32, u32)) -> Result<Grid, Error> {
+        self.check_scale_level()?;
+        self.quantizator.check_area(origin, input.dimensions())?;
+        Ok(self.encode_reconstructed(input, origin).0)
+    }
+
+    // Returns the grid along with the image it decodes to
+    fn encode_reconstructed(&self, input: GrayImage, (x, y): (u32, u32)) -> (Grid, GrayImage) {
         let (width, height) = input.dimensions();
         let levels = self.scale_level;
         let mut grid = Grid::new(width as usize, height as usize);
         self.initialize_first_level(&input, &mut grid);
 
+        // the input is replaced by its reconstruction level by level
+        let mut state = (input, grid);
         for level in 0..levels {
-            let process_pixel = #[inline(always)]
-            |column, line| {
-                let prediction =
-                    self.interpolator
-                        .interpolate(levels, level + 1, (column, line), &input);
-
-                let actual_value = unsafe { input.unsafe_get_pixel(column, line).data[0] };
-                let diff = actual_value.wrapping_sub(prediction);
-                let mut quanted_diff = self.quantizator.quantize(diff);
-
-                let overflow = prediction.checked_add(quanted_diff).is_none();
-                let overflow_is_expected = prediction.checked_add(diff).is_none();
-                if overflow != overflow_is_expected {
-                    quanted_diff = diff;
+            let quantize_segment = #[inline(always)]
+            |segment: Segment, (input, _): &(GrayImage, Grid), buffer: &mut Vec<u8>| {
+                // quantized differences followed by reconstructed values, actual values at the end
+                let count = segment.count as usize;
+                let start = buffer.len();
+                buffer.resize(start + 3 * count, 0);
+                let (quanted_diffs, rest) = buffer[start..].split_at_mut(count);
+                let (values, actual) = rest.split_at_mut(count);
+
+                self.interpolator
+                    .interpolate_segment(levels, level + 1, segment, input, values);
+                for (actual, &pixel) in actual.iter_mut().zip(segment_pixels(input, segment)) {
+                    *actual = pixel;
                 }
+                self.quantizator.quantize_segment((x, y), segment, values, actual, quanted_diffs);
+                for (value, &quanted_diff) in values.iter_mut().zip(&*quanted_diffs) {
+                    *value = value.wrapping_add(quanted_diff);
+                }
+                buffer.truncate(start + 2 * count);
+            };
 
-                unsafe { grid.set((column, line), quanted_diff) };
-                let pixel = gray(prediction.wrapping_add(quanted_diff));
-                unsafe { input.unsafe_put_pixel(column, line, pixel) };
+            let store_segment = #[inline(always)]
+            |segment: Segment, buffer: &[u8], (input, grid): &mut (GrayImage, Grid)| {
+                let (quanted_diffs, values) = buffer.split_at(segment.count as usize);
+                for (&quanted_diff, column) in quanted_diffs.iter().zip(segment.columns()) {
+                    unsafe { grid.set_unchecked((column, segment.line), quanted_diff) };
+                }
+                for (pixel, &value) in segment_pixels_mut(input, segment).zip(values) {
+                    *pixel = value;
+                }
             };
 
-            traverse_level(level, levels, 0, width, 0, height, process_pixel);
+            traverse_segments_with(level, levels, (0, width, 0, height), &mut state, quantize_segment, store_segment);
+        }
+
+        let (input, grid) = state;
+        (grid, input)
+    }
+
+    /// Encodes every tile of `image` into the archive, a tile per thread at a time.
+    /// The archive does not depend on the number of threads.
+    pub fn encode_tiles<W: Write>(
+        &mut self,
+        image: &GrayImage,
+        writer: &mut ArchiveWriter<W>,
+    ) -> Result<(), Error> {
+        self.check_scale_level()?;
+        self.quantizator.check_area((0, 0), image.dimensions())?;
+        let encoder = &*self;
+        let mut tiles = writer.tiles();
+        loop {
+            let batch: Vec<Rect> = tiles.by_ref().take(batch_size()).collect();
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let grids = map_ordered(batch, |rect| {
+                encoder.encode_reconstructed(crop(image, rect), (rect.x, rect.y)).0
+            });
+            for grid in grids {
+                writer.write_tile(&grid)?;
+            }
         }
+    }
 
-        grid
+    /// Encodes an untiled image as a base grid followed by quality layers refining
+    /// it down to each of `errors` in turn.
+    pub fn encode_layers<W: Write>(
+        &mut self,
+        image: &GrayImage,
+        errors: &[u8],
+        writer: &mut ArchiveWriter<W>,
+    ) -> Result<(), Error> {
+        self.check_scale_level()?;
+        self.quantizator.check_area((0, 0), image.dimensions())?;
+        if writer.tile_count() != 1 {
+            return Err(invalid("tiled archives have no quality layers"));
+        }
+
+        let (grid, mut decoded) = self.encode_reconstructed(image.clone(), (0, 0));
+        writer.write_tile(&grid)?;
+
+        for &error in errors {
+            let quantizator = Linear::new(error)?;
+            let mut residues = Vec::with_capacity(image.len());
+            for (pixel, actual) in decoded.pixels_mut().zip(image.pixels()) {
+                let residue = quantizator.quantize((0, 0), pixel.data[0], actual.data[0]);
+                pixel.data[0] = pixel.data[0].wrapping_add(residue);
+                residues.push(residue);
+            }
+            writer.write_layer(&Layer { max_error: error, residues })?;
+        }
+        Ok(())
+    }
+}
+
+/// Lines of a `StreamEncoder` band unless the metadata sets the tiling.
+/// Every band is coded on its own, so prediction restarts at its top line: on a 1960x1960 photo
+/// 16 line bands grow the archive by 16% lossless and 67% at `Low`, 256 line ones by 1.4% and 4%.
+pub const DEFAULT_BAND_HEIGHT: u32 = 256;
+
+/// Encodes an image fed by rows into a tiled archive of full width bands, writing every band
+/// as soon as its rows arrive. Only the rows of the current band are held in memory.
+pub struct StreamEncoder<I, Q, W: Write> {
+    encoder: Encoder<I, Q>,
+    writer: ArchiveWriter<W>,
+    width: u32,
+    height: u32,
+    band_height: u32,
+    // first line of the current band
+    line: u32,
+    rows: Vec<u8>,
+}
+
+impl<I, Q, W> StreamEncoder<I, Q, W>
+where
+    Q: Quantizator,
+    I: Interpolator,
+    W: Write,
+{
+    /// Writes the archive header, without tiling the image is split into bands of `DEFAULT_BAND_HEIGHT`
+    /// lines, or `2^scale_level` when more. A given tiling must span the whole width, its tile height
+    /// trades the memory held for a band against the size of the archive.
+    pub fn new(encoder: Encoder<I, Q>, w: W, mut metadata: Metadata) -> Result<Self, Error> {
+        let (width, height) = (metadata.width, metadata.height);
+        if width == 0 || height == 0 {
+            return Err(invalid_dimensions("image must not be empty"));
+        }
+        encoder.check_scale_level()?;
+        let tiling = match metadata.tiling {
+            Some(tiling) if tiling.tile_width < width => return Err(invalid("tiles must span the image width")),
+            Some(tiling) => {
+                tiling.check()?;
+                tiling
+            }
+            None => Tiling::new(width, DEFAULT_BAND_HEIGHT.max(1 << metadata.scale_level))?,
+        };
+        metadata.tiling = Some(tiling);
+
+        Ok(StreamEncoder {
+            encoder,
+            writer: ArchiveWriter::new(w, &metadata)?,
+            width,
+            height,
+            band_height: tiling.tile_height,
+            line: 0,
+            rows: Vec::new(),
+        })
+    }
+
+    /// Appends rows of `width` pixels each, encoding every band they complete.
+    pub fn push_rows(&mut self, rows: &[u8]) -> Result<(), Error> {
+        let width = self.width as usize;
+        if !rows.len().is_multiple_of(width) {
+            return Err(invalid_dimensions("rows must be whole"));
+        }
+        let pushed = (self.rows.len() + rows.len()) / width;
+        if pushed as u64 > u64::from(self.height - self.line) {
+            return Err(invalid_dimensions("more rows than the image height"));
+        }
+
+        let mut rows = rows;
+        while !rows.is_empty() {
+            let lines = self.band_height.min(self.height - self.line) as usize;
+            let take = (lines * width - self.rows.len()).min(rows.len());
+            self.rows.extend_from_slice(&rows[..take]);
+            rows = &rows[take..];
+            if self.rows.len() == lines * width {
+                self.encode_band(lines as u32)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_band(&mut self, lines: u32) -> Result<(), Error> {
+        let rows = ::std::mem::take(&mut self.rows);
+        let band = GrayImage::from_raw(self.width, lines, rows).expect("band holds whole rows");
+        let grid = self.encoder.encode_tile(band, (0, self.line))?;
+        self.writer.write_tile(&grid)?;
+        self.line += lines;
+        Ok(())
+    }
+
+    /// Number of rows pushed so far.
+    pub fn rows(&self) -> u32 {
+        self.line + (self.rows.len() / self.width as usize) as u32
+    }
+
+    /// Ends the archive once every row is pushed.
+    pub fn finish(self) -> Result<W, Error> {
+        if self.line != self.height {
+            return Err(invalid(format!("{} of {} rows pushed", self.rows(), self.height)));
+        }
+        self.writer.finish()
+    }
+}
+
+/// How the grid is laid out in the archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Coding {
+    /// The whole image at once, level by level, so that any prefix decodes to a preview
+    Progressive,
+    /// Independently coded square tiles of this size
+    Tiled(u32),
+    /// Quality layers refining the image down to each of these errors in turn
+    Layered(Vec<u8>),
+}
+
+/// Everything needed to encode an image in a single call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncoderConfig {
+    /// Maximum difference between a source pixel and its decoded value, up to `MAX_ERROR`
+    pub max_error: u8,
+    pub levels: usize,
+    pub interpolation: InterpolationType,
+    pub coding: Coding,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        EncoderConfig {
+            max_error: QuantizationLevel::Medium.error(),
+            levels: 4,
+            interpolation: InterpolationType::Crossed,
+            coding: Coding::Progressive,
+        }
+    }
+}
+
+impl EncoderConfig {
+    pub fn max_error(mut self, max_error: u8) -> Self {
+        self.max_error = max_error;
+        self
+    }
+
+    pub fn levels(mut self, levels: usize) -> Self {
+        self.levels = levels;
+        self
+    }
+
+    pub fn interpolation(mut self, interpolation: InterpolationType) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    pub fn coding(mut self, coding: Coding) -> Self {
+        self.coding = coding;
+        self
+    }
+
+    /// Encodes a grayscale image into an archive written to `w`, returns the writer.
+    pub fn encode_to_writer<W: Write>(&self, image: &DynamicImage, w: W) -> Result<W, Error> {
+        let image = match *image {
+            DynamicImage::ImageLuma8(ref image) => image,
+            ref image => return Err(Error::UnsupportedColorType(image.color())),
+        };
+        if self.max_error > MAX_ERROR {
+            return Err(invalid(format!("maximum error must not exceed {}", MAX_ERROR)));
+        }
+        if self.interpolation == InterpolationType::Line {
+            return Err(Error::UnsupportedInterpolation(self.interpolation.clone()));
+        }
+
+        // errors between the quantization levels are stored as a uniform error map
+        let (width, height) = image.dimensions();
+        let (quantization_level, error_map) = match QuantizationLevel::from_error(self.max_error) {
+            Some(level) => (level, None),
+            None => (QuantizationLevel::Lossless, Some(ErrorMap::uniform(width, height, self.max_error))),
+        };
+        let tiling = match self.coding {
+            Coding::Tiled(size) => Some(Tiling::new(size, size)?),
+            _ => None,
+        };
+        let metadata = Metadata {
+            quantization_level,
+            interpolation: self.interpolation.clone(),
+            width,
+            height,
+            scale_level: self.levels,
+            error_map,
+            tiling,
+        };
+
+        let mut writer = ArchiveWriter::new(w, &metadata)?;
+        let quantizator = Linear::new(self.max_error)?;
+        match self.interpolation {
+            InterpolationType::Previous => {
+                self.encode_with(Encoder::new(LeftTop, quantizator, self.levels), image, &mut writer)?
+            }
+            _ => self.encode_with(Encoder::new(Crossed, quantizator, self.levels), image, &mut writer)?,
+        }
+        writer.finish()
+    }
+
+    fn encode_with<I: Interpolator, W: Write>(
+        &self,
+        mut encoder: Encoder<I, Linear>,
+        image: &GrayImage,
+        writer: &mut ArchiveWriter<W>,
+    ) -> Result<(), Error> {
+        match self.coding {
+            Coding::Layered(ref errors) => encoder.encode_layers(image, errors, writer),
+            _ => encoder.encode_tiles(image, writer),
+        }
     }
 }
@@ -0,0 +1,103 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use bincode;
+use image::ColorType;
+
+use archive::Tag;
+use interpolator::InterpolationType;
+
+/// Failures of the library.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The data does not start with the magic number of an archive
+    BadMagic(u32),
+    /// The archive was written by a newer version of the format
+    UnsupportedVersion(u16),
+    /// The archive holds a critical chunk this version does not understand
+    UnsupportedChunk(Tag),
+    /// The archive ends before its end chunk
+    Truncated,
+    /// Checksum mismatch or malformed contents
+    Corrupted(String),
+    /// Image, grid, tile or layer sizes that do not fit together
+    InvalidDimensions(String),
+    /// Only grayscale images are coded
+    UnsupportedColorType(ColorType),
+    /// Interpolation without an implementation
+    UnsupportedInterpolation(InterpolationType),
+    /// The archive lacks what the operation relies on
+    Unsupported(String),
+    /// Arguments out of range or calls out of order
+    InvalidArgument(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::BadMagic(magic) => write!(f, "incorrect magic number {:#010x}", magic),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            Error::UnsupportedChunk(tag) => {
+                write!(f, "unsupported critical chunk {:?}", String::from_utf8_lossy(&tag))
+            }
+            Error::Truncated => write!(f, "truncated archive: unexpected end of archive"),
+            Error::Corrupted(ref reason) => write!(f, "corrupted archive: {}", reason),
+            Error::InvalidDimensions(ref reason) => write!(f, "invalid dimensions: {}", reason),
+            Error::UnsupportedColorType(color) => write!(f, "unsupported color type {:?}", color),
+            Error::UnsupportedInterpolation(ref interpolation) => {
+                write!(f, "unsupported interpolation {:?}", interpolation)
+            }
+            Error::Unsupported(ref reason) => write!(f, "unsupported: {}", reason),
+            Error::InvalidArgument(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// Archive contents that fail to deserialize are corrupted, unless the stream ran out
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => truncated(e),
+            ref e => corrupted(e.to_string()),
+        }
+    }
+}
+
+pub fn corrupted<S: Into<String>>(reason: S) -> Error {
+    Error::Corrupted(reason.into())
+}
+
+pub fn invalid<S: Into<String>>(reason: S) -> Error {
+    Error::InvalidArgument(reason.into())
+}
+
+pub fn invalid_dimensions<S: Into<String>>(reason: S) -> Error {
+    Error::InvalidDimensions(reason.into())
+}
+
+// Reading past the end of the archive means it was truncated
+pub fn truncated(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::Truncated
+    } else {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,169 @@
+use std::convert::TryFrom;
+
+use image::GrayImage;
+
+use archive::parse;
+use error::{corrupted, invalid, Error};
+use quantizator::MAX_ERROR;
+
+pub const DEFAULT_BLOCK_SHIFT: u8 = 3;
+
+/// Maximum allowed error for every block of `2^block_shift x 2^block_shift` pixels.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(into = "ErrorRuns", try_from = "ErrorRuns")]
+pub struct ErrorMap {
+    block_shift: u8,
+    width: u32,
+    height: u32,
+    columns: u32,
+    errors: Vec<u8>,
+}
+
+impl ErrorMap {
+    /// Builds the map from an image whose pixel values are the allowed errors.
+    /// Each block gets the smallest error of its pixels, `block_shift` must be less than 32.
+    pub fn from_image(map: &GrayImage, block_shift: u8) -> Result<Self, Error> {
+        let (width, height) = map.dimensions();
+        let mut error_map = ErrorMap::new(width, height, block_shift, MAX_ERROR)?;
+        for (x, y, pixel) in map.enumerate_pixels() {
+            let index = error_map.index((x, y));
+            let error = &mut error_map.errors[index];
+            *error = (*error).min(pixel.data[0].min(MAX_ERROR));
+        }
+        Ok(error_map)
+    }
+
+    /// Builds the map from a region of interest mask: blocks touching a non-zero
+    /// mask pixel get `inside` error, the rest get `outside`. `block_shift` must be less than 32.
+    pub fn from_mask(mask: &GrayImage, block_shift: u8, inside: u8, outside: u8) -> Result<Self, Error> {
+        let (width, height) = mask.dimensions();
+        let mut error_map = ErrorMap::new(width, height, block_shift, outside.min(MAX_ERROR))?;
+        for (x, y, pixel) in mask.enumerate_pixels() {
+            if pixel.data[0] != 0 {
+                let index = error_map.index((x, y));
+                error_map.errors[index] = inside.min(MAX_ERROR);
+            }
+        }
+        Ok(error_map)
+    }
+
+    /// Builds the map allowing the same error everywhere.
+    pub fn uniform(width: u32, height: u32, error: u8) -> Self {
+        ErrorMap::new(width, height, DEFAULT_BLOCK_SHIFT, error.min(MAX_ERROR)).expect("default block shift is valid")
+    }
+
+    fn new(width: u32, height: u32, block_shift: u8, error: u8) -> Result<Self, Error> {
+        check_block_shift(block_shift).map_err(invalid)?;
+        let columns = blocks(width, block_shift);
+        let lines = blocks(height, block_shift);
+        Ok(ErrorMap {
+            block_shift,
+            width,
+            height,
+            columns,
+            errors: vec![error; columns as usize * lines as usize],
+        })
+    }
+
+    #[inline(always)]
+    fn index(&self, (x, y): (u32, u32)) -> usize {
+        let column = x >> self.block_shift;
+        let line = y >> self.block_shift;
+        line as usize * self.columns as usize + column as usize
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    #[inline(always)]
+    pub fn error_at(&self, at: (u32, u32)) -> u8 {
+        self.errors[self.index(at)]
+    }
+
+    pub fn max_error(&self) -> u8 {
+        self.errors.iter().cloned().max().unwrap_or(0)
+    }
+}
+
+impl ErrorMap {
+    /// Reads an archived map, checking its dimensions before building the blocks.
+    pub(crate) fn deserialize(data: &[u8], width: u32, height: u32) -> Result<Self, Error> {
+        let runs: ErrorRuns = parse(data)?;
+        if (runs.width, runs.height) != (width, height) {
+            return Err(corrupted("error map dimensions differ from the image"));
+        }
+        ErrorMap::try_from(runs).map_err(corrupted)
+    }
+}
+
+// Blocks are indexed by shifted u32 coordinates
+fn check_block_shift(block_shift: u8) -> Result<(), String> {
+    if block_shift >= 32 {
+        return Err(format!("incorrect error map block shift {}", block_shift));
+    }
+    Ok(())
+}
+
+fn blocks(size: u32, block_shift: u8) -> u32 {
+    ((u64::from(size) + (1 << block_shift) - 1) >> block_shift) as u32
+}
+
+// Archived form of ErrorMap: block errors are run-length encoded in line order
+#[derive(Serialize, Deserialize)]
+struct ErrorRuns {
+    block_shift: u8,
+    width: u32,
+    height: u32,
+    runs: Vec<(u8, u32)>,
+}
+
+impl From<ErrorMap> for ErrorRuns {
+    fn from(map: ErrorMap) -> Self {
+        let mut runs: Vec<(u8, u32)> = Vec::new();
+        for &error in &map.errors {
+            match runs.last_mut() {
+                Some(&mut (value, ref mut length)) if value == error => *length += 1,
+                _ => runs.push((error, 1)),
+            }
+        }
+
+        ErrorRuns {
+            block_shift: map.block_shift,
+            width: map.width,
+            height: map.height,
+            runs,
+        }
+    }
+}
+
+impl TryFrom<ErrorRuns> for ErrorMap {
+    type Error = String;
+
+    fn try_from(runs: ErrorRuns) -> Result<Self, Self::Error> {
+        check_block_shift(runs.block_shift)?;
+
+        let columns = blocks(runs.width, runs.block_shift);
+        let expected = u64::from(columns) * u64::from(blocks(runs.height, runs.block_shift));
+        let actual: u64 = runs.runs.iter().map(|&(_, length)| u64::from(length)).sum();
+        if actual != expected {
+            return Err(format!("error map has {} blocks, expected {}", actual, expected));
+        }
+
+        // the sizes are untrusted, the blocks grow with the runs instead of being reserved up front
+        let mut errors = Vec::new();
+        for (error, length) in runs.runs {
+            if error > MAX_ERROR {
+                return Err(format!("error map value {} exceeds {}", error, MAX_ERROR));
+            }
+            errors.extend(::std::iter::repeat_n(error, length as usize));
+        }
+        Ok(ErrorMap {
+            block_shift: runs.block_shift,
+            width: runs.width,
+            height: runs.height,
+            columns,
+            errors,
+        })
+    }
+}
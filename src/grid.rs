@@ -1,34 +1,166 @@
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+use std::convert::TryFrom;
+
+use utils::traverse_level;
+
+/// Residues of an encoded image, a value per pixel in line order.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[serde(into = "RawGrid", try_from = "RawGrid")]
 pub struct Grid {
     buffer: Vec<u8>,
-    width: usize
+    width: usize,
+    height: usize,
 }
 
 impl Grid {
+    /// Panics when the number of nodes overflows `usize`.
     pub fn new(width: usize, height: usize) -> Self {
-        let size = width * height;
-        let mut buffer = Vec::with_capacity(size);
-        unsafe { buffer.set_len(size) };
+        let size = width.checked_mul(height).expect("grid size overflows usize");
+        let buffer = vec![0; size];
 
         Grid {
             buffer,
-            width
+            width,
+            height
+        }
+    }
+
+    /// Wraps the values of a `width x height` grid, None when their number doesn't match.
+    pub fn from_vec(width: usize, height: usize, buffer: Vec<u8>) -> Option<Self> {
+        match width.checked_mul(height) {
+            Some(size) if size == buffer.len() => Some(Grid { buffer, width, height }),
+            _ => None,
         }
     }
 
+    /// Panics when the node is outside the grid.
+    #[inline(always)]
+    pub fn set(&mut self, (column, line): (u32, u32), value: u8) {
+        let index = self.index(column, line);
+        self.buffer[index] = value;
+    }
+
+    /// Panics when the node is outside the grid.
+    #[inline(always)]
+    pub fn get(&self, column: u32, line: u32) -> u8 {
+        self.buffer[self.index(column, line)]
+    }
+
     #[inline(always)]
-    pub unsafe fn set(&mut self, (column, line): (u32, u32), value: u8) {
+    fn index(&self, column: u32, line: u32) -> usize {
+        assert!(
+            (column as usize) < self.width && (line as usize) < self.height,
+            "node ({}, {}) is outside of the {}x{} grid", column, line, self.width, self.height
+        );
+        line as usize * self.width + column as usize
+    }
+
+    /// The node must lie inside the grid.
+    #[inline(always)]
+    pub(crate) unsafe fn set_unchecked(&mut self, (column, line): (u32, u32), value: u8) {
         *self.buffer.get_unchecked_mut(line as usize * self.width + column as usize) = value;
     }
 
+    /// The node must lie inside the grid.
     #[inline(always)]
-    pub unsafe fn get(&self, column: u32, line: u32) -> u8 {
+    pub(crate) unsafe fn get_unchecked(&self, column: u32, line: u32) -> u8 {
         *self.buffer.get_unchecked(line as usize * self.width + column as usize)
     }
 
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Values of the first level followed by residues of every level in coding order.
+    /// The first `n + 1` of them form the grid of the image downscaled `2^(levels - n)` times.
+    pub fn levels(&self, levels: usize) -> Vec<Vec<u8>> {
+        let mut data = vec![Vec::new(); levels + 1];
+        traverse_levels(self.width, self.height, levels, |level, index| data[level].push(self.buffer[index]));
+        data
+    }
+
+    /// Restores the grid from its `levels + 1` levels, None when their sizes don't match it.
+    pub fn from_levels(width: usize, height: usize, levels: usize, data: &[Vec<u8>]) -> Option<Self> {
+        if data.len() != levels.checked_add(1)? {
+            return None;
+        }
+        // sizes are checked before anything is allocated for the claimed dimensions
+        let mut size = level_size(width, height, levels)?;
+        for (level, values) in data.iter().enumerate() {
+            if level > 0 {
+                let shift = levels - level;
+                size = level_size(width, height, shift)? - level_size(width, height, shift + 1)?;
+            }
+            if values.len() != size {
+                return None;
+            }
+        }
+
+        let mut positions = vec![0; data.len()];
+        let mut buffer = vec![0; width * height];
+        traverse_levels(width, height, levels, |level, index| {
+            buffer[index] = data[level][positions[level]];
+            positions[level] += 1;
+        });
+        Some(Grid { buffer, width, height })
+    }
+
     pub fn print(&self) {
         for value in self.buffer.iter() {
             print!("{} ", value);
         }
     }
-}
\ No newline at end of file
+}
+
+// Visits nodes of the first level and then of every level in coding order
+fn traverse_levels<F>(width: usize, height: usize, levels: usize, mut f: F)
+where
+    F: FnMut(usize, usize),
+{
+    let (width, height, stride) = (width as u32, height as u32, width);
+    let step = 1 << levels;
+    for line in (0..height).step_by(step) {
+        for column in (0..width).step_by(step) {
+            f(0, line as usize * stride + column as usize);
+        }
+    }
+
+    for level in 0..levels {
+        let f = &mut f;
+        traverse_level(level, levels, 0, width, 0, height, |column, line| {
+            f(level + 1, line as usize * stride + column as usize)
+        });
+    }
+}
+
+// Number of nodes `2^shift` apart, None when it overflows
+fn level_size(width: usize, height: usize, shift: usize) -> Option<usize> {
+    let step = u32::try_from(shift).ok().and_then(|shift| 1usize.checked_shl(shift))?;
+    width.div_ceil(step).checked_mul(height.div_ceil(step))
+}
+
+// Archived form of Grid, the height follows from the number of values
+#[derive(Serialize, Deserialize)]
+struct RawGrid {
+    buffer: Vec<u8>,
+    width: usize,
+}
+
+impl From<Grid> for RawGrid {
+    fn from(grid: Grid) -> Self {
+        RawGrid {
+            buffer: grid.buffer,
+            width: grid.width,
+        }
+    }
+}
+
+impl TryFrom<RawGrid> for Grid {
+    type Error = String;
+
+    fn try_from(raw: RawGrid) -> Result<Self, Self::Error> {
+        let (length, width) = (raw.buffer.len(), raw.width);
+        let height = length.checked_div(width).unwrap_or(0);
+        Grid::from_vec(width, height, raw.buffer)
+            .ok_or_else(|| format!("grid of {} values doesn't fit width {}", length, width))
+    }
+}
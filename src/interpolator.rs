@@ -1,15 +1,42 @@
 use image::GrayImage;
 
+use simd;
 
+
+arg_enum! {
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum InterpolationType {
     Crossed,
     Line,
     Previous,
 }
+}
 
-pub trait Interpolator {
+/// Nodes of a level on one line: `count` columns from `start` spaced by `spacing`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub line: u32,
+    pub start: u32,
+    pub spacing: u32,
+    pub count: u32,
+}
+
+impl Segment {
+    pub fn columns(&self) -> impl Iterator<Item = u32> {
+        let segment = *self;
+        (0..segment.count).map(move |i| segment.start + i * segment.spacing)
+    }
+}
+
+pub trait Interpolator: Sync {
     fn interpolate(&self, levels: usize, level: usize, at: (u32, u32), image: &GrayImage) -> u8;
+
+    /// Predicts every node of the segment exactly as `interpolate` does.
+    fn interpolate_segment(&self, levels: usize, level: usize, segment: Segment, image: &GrayImage, predictions: &mut [u8]) {
+        for (prediction, column) in predictions.iter_mut().zip(segment.columns()) {
+            *prediction = self.interpolate(levels, level, (column, segment.line), image);
+        }
+    }
 }
 
 pub struct LeftTop; // almost no-op
@@ -41,16 +68,7 @@ struct CrossedValues {
 impl CrossedValues {
     #[inline(always)]
     pub fn prediction(&self) -> u8 {
-        let average = |x, y| (x as usize + y as usize + 1) >> 1; // div 2
-
-        let left  = average(self.left_top,  self.left_bot);
-        let right = average(self.right_bot, self.right_top);
-        let top   = average(self.right_top, self.left_top);
-        let bot   = average(self.right_bot, self.left_bot);
-
-        let average = (left + right + top + bot) >> 2; // div 4
-
-        average as u8
+        simd::predict(self.left_top, self.left_bot, self.right_top, self.right_bot)
     }
 }
 
@@ -88,4 +106,51 @@ impl Interpolator for Crossed {
             right_bot: get_pixel(x_bot, y_right)
         }.prediction()
     }
-}
\ No newline at end of file
+
+    // Nodes of a segment share the corners of the cells along two coarse lines,
+    // so the predictions of all the cells are computed at once
+    fn interpolate_segment(&self, levels: usize, level: usize, segment: Segment, image: &GrayImage, predictions: &mut [u8]) {
+        if segment.count == 0 {
+            return;
+        }
+
+        let shift = levels - level + 1;
+        let step = 1usize << shift;
+        let y_left = segment.line as usize & !(step - 1);
+        let first = (segment.start >> shift) as usize;
+        let last = ((segment.start + (segment.count - 1) * segment.spacing) >> shift) as usize;
+        let cells = last - first + 1;
+
+        // corners of the cells along two lines, zero outside the image, and their predictions
+        let mut buffer = vec![0; 3 * cells + 2];
+        let (upper, rest) = buffer.split_at_mut(cells + 1);
+        let (lower, values) = rest.split_at_mut(cells + 1);
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        for (corners, y) in [(&mut *upper, y_left), (&mut *lower, y_left + step)] {
+            if y < height && first << shift < width {
+                let row = &image.as_ref()[y * width + (first << shift)..(y + 1) * width];
+                for (corner, &pixel) in corners.iter_mut().zip(row.iter().step_by(step)) {
+                    *corner = pixel;
+                }
+            }
+        }
+
+        let substep = (step / 2) as u32;
+        let aligned = segment.start as usize == first << shift;
+        if segment.spacing == substep << 1 && predictions.len() == cells {
+            // nodes in the middle of every cell
+            simd::predict_cells(upper, lower, predictions);
+        } else if segment.spacing == substep && aligned {
+            // nodes on the corner and in the middle of every cell
+            simd::predict_cells(upper, lower, values);
+            for (pair, &value) in predictions.chunks_mut(2).zip(&*values) {
+                pair.fill(value);
+            }
+        } else {
+            simd::predict_cells(upper, lower, values);
+            for (prediction, column) in predictions.iter_mut().zip(segment.columns()) {
+                *prediction = values[(column >> shift) as usize - first];
+            }
+        }
+    }
+}
@@ -2,8 +2,11 @@
 
 extern crate bincode;
 extern crate byteorder;
+extern crate crc32fast;
 extern crate flate2;
 extern crate image;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 extern crate serde;
 #[macro_use]
 extern crate clap;
@@ -11,27 +14,51 @@ extern crate clap;
 extern crate serde_derive;
 
 mod archive;
+mod codec;
 mod decoder;
 mod encoder;
+mod error;
+pub mod error_map;
 pub mod interpolator;
+pub mod metrics;
 pub mod quantizator;
+mod simd;
 mod utils;
 mod grid;
+pub mod tiling;
 
-pub use self::archive::{Archive, Metadata};
-pub use self::decoder::Decoder;
-pub use self::encoder::Encoder;
+pub use self::archive::{Archive, ArchiveReader, ArchiveWriter, Layer, Metadata, PayloadSize};
+pub use self::codec::{load_from_memory, open, HgiDecoder, HgiEncoder};
+pub use self::decoder::{
+    decode_archive, decode_archive_strict, decode_archive_to_level, decode_from_reader, Decoder, StreamDecoder,
+};
+pub use self::encoder::{Coding, Encoder, EncoderConfig, StreamEncoder, DEFAULT_BAND_HEIGHT};
+pub use self::error::Error;
+pub use self::grid::Grid;
 
 #[cfg(test)]
 mod tests {
-    use image::{GrayImage, Luma};
-    use std::io;
+    use image::{ColorType, DecodingResult, DynamicImage, GrayImage, ImageDecoder, Luma, RgbImage};
+    use std::io::{self, Write};
+    use std::panic;
 
-    use archive::{Archive, Metadata};
-    use decoder::Decoder;
-    use encoder::Encoder;
-    use interpolator::{Crossed, InterpolationType};
-    use quantizator::{Linear, QuantizationLevel, Quantizator};
+    use bincode;
+    use byteorder::{WriteBytesExt, LE};
+    use flate2::{write::DeflateEncoder, Compression};
+
+    use archive::{self, Archive, ArchiveReader, ArchiveWriter, Layer, Metadata, PayloadSize};
+    use codec::{self, HgiDecoder, HgiEncoder};
+    use decoder::{decode_from_reader, Decoder, StreamDecoder};
+    use encoder::{Coding, Encoder, EncoderConfig, StreamEncoder, DEFAULT_BAND_HEIGHT};
+    use error::Error;
+    use error_map::ErrorMap;
+    use grid::Grid;
+    use interpolator::{Crossed, InterpolationType, Interpolator};
+    use metrics::{self, Metrics};
+    use quantizator::{self, Linear, QuantizationLevel, Quantizator, Regional};
+    use simd;
+    use tiling::{Rect, Tiling};
+    use utils;
 
     fn get_test_image(width: u32, height: u32) -> GrayImage {
         let mut image = GrayImage::new(width, height);
@@ -55,23 +82,21 @@ mod tests {
         let max_error = quantizator.error() as usize;
         let interpolator = Crossed;
         let mut encoder = Encoder::new(interpolator, quantizator, levels);
-        let grid = encoder.encode(image.clone());
+        let grid = encoder.encode(image.clone()).unwrap();
 
         let mut decoder = Decoder::new(Crossed);
-        let image = decoder.decode((width, height),levels, &grid);
+        let decoded = decoder.decode((width, height),levels, &grid).unwrap();
 
-        let line: String = ::std::iter::repeat('-')
-            .take(image.width() as usize * 4)
-            .collect();
+        let line = "-".repeat(decoded.width() as usize * 4);
         println!("{}", line);
-        for line in image.chunks(image.width() as usize) {
+        for line in decoded.chunks(decoded.width() as usize) {
             println!("{:2?}", line);
         }
 
         for (x, y, pixel) in image.enumerate_pixels() {
             let before = i32::from(pixel.data[0]);
-            let after = i32::from(image[(x, y)].data[0]);
-            let diff = (before - after).abs() as usize;
+            let after = i32::from(decoded[(x, y)].data[0]);
+            let diff = (before - after).unsigned_abs() as usize;
             assert!(diff <= max_error);
         }
     }
@@ -96,6 +121,163 @@ mod tests {
         test_error(QuantizationLevel::High);
     }
 
+    #[test]
+    fn linear_error_bound() {
+        for error in 0..=quantizator::MAX_ERROR {
+            let quantizator = Linear::new(error).unwrap();
+            for prediction in 0..=255u8 {
+                for actual in 0..=255u8 {
+                    let residue = quantizator.quantize((0, 0), prediction, actual);
+                    let restored = prediction.wrapping_add(residue);
+                    let diff = (i32::from(actual) - i32::from(restored)).abs();
+                    assert!(
+                        diff <= i32::from(error),
+                        "error {}: prediction {}, actual {}, restored {}",
+                        error, prediction, actual, restored
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn saturated_image_error() {
+        let levels = 3;
+        let (width, height) = (16, 16);
+        let mut image = GrayImage::new(width, height);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Luma([if (x + y) % 3 == 0 { 255 } else { 250 - (x * y) as u8 % 8 }]);
+        }
+
+        for &level in &[QuantizationLevel::Low, QuantizationLevel::Medium, QuantizationLevel::High] {
+            let quantizator = Linear::from(level);
+            let max_error = i32::from(quantizator.error());
+            let mut encoder = Encoder::new(Crossed, quantizator, levels);
+            let grid = encoder.encode(image.clone()).unwrap();
+            let decoded = Decoder::new(Crossed).decode((width, height), levels, &grid).unwrap();
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let diff = i32::from(pixel.data[0]) - i32::from(decoded[(x, y)].data[0]);
+                assert!(diff.abs() <= max_error);
+            }
+        }
+    }
+
+    #[test]
+    fn region_of_interest() {
+        let levels = 3;
+        let (width, height) = (40, 24);
+        let image = get_test_image(width, height);
+        let mut mask = GrayImage::new(width, height);
+        for (x, y, pixel) in mask.enumerate_pixels_mut() {
+            *pixel = Luma([if (10..20).contains(&x) && (5..15).contains(&y) { 255 } else { 0 }]);
+        }
+
+        let map = ErrorMap::from_mask(&mask, 2, 0, QuantizationLevel::High.error()).unwrap();
+        let mut encoder = Encoder::new(Crossed, Regional::new(map.clone()), levels);
+        let grid = encoder.encode(image.clone()).unwrap();
+        let decoded = Decoder::new(Crossed).decode((width, height), levels, &grid).unwrap();
+
+        let mut lossy = 0;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let diff = (i32::from(pixel.data[0]) - i32::from(decoded[(x, y)].data[0])).abs();
+            assert!(diff <= i32::from(map.error_at((x, y))));
+            if mask[(x, y)].data[0] != 0 {
+                assert_eq!(diff, 0);
+            } else if diff != 0 {
+                lossy += 1;
+            }
+        }
+        assert!(lossy > 0);
+    }
+
+    #[test]
+    fn serde_error_map() {
+        let (width, height) = (30, 20);
+        let mut errors = GrayImage::new(width, height);
+        for (x, _, pixel) in errors.enumerate_pixels_mut() {
+            *pixel = Luma([if x < 16 { 0 } else { 200 }]);
+        }
+        let map = ErrorMap::from_image(&errors, 3).unwrap();
+        assert_eq!(map.error_at((0, 0)), 0);
+        assert_eq!(map.error_at((29, 19)), quantizator::MAX_ERROR);
+        assert!(ErrorMap::from_image(&errors, 31).is_ok());
+        for &block_shift in &[32, 64, u8::MAX] {
+            match ErrorMap::from_image(&errors, block_shift) {
+                Err(Error::InvalidArgument(_)) => {}
+                _ => panic!("error map with {} block shift was built", block_shift),
+            }
+            assert!(ErrorMap::from_mask(&errors, block_shift, 0, 0).is_err());
+        }
+
+        let image = get_test_image(width, height);
+        let mut encoder = Encoder::new(Crossed, Regional::new(map.clone()), 2);
+        let grid = encoder.encode(image).unwrap();
+        let metadata = Metadata {
+            quantization_level: QuantizationLevel::Lossless,
+            interpolation: InterpolationType::Crossed,
+            width,
+            height,
+            scale_level: 2,
+            error_map: Some(map),
+            tiling: None,
+        };
+        assert_eq!(metadata.max_error(), quantizator::MAX_ERROR);
+
+        let archive = Archive { metadata, grid };
+        let mut buffer = Vec::new();
+        archive.serialize_to_writer(&mut buffer).unwrap();
+        let res = Archive::deserialize_from_reader(&mut io::Cursor::new(&buffer));
+        assert_eq!(res.unwrap(), archive);
+    }
+
+    #[test]
+    fn reject_mismatched_error_map() {
+        let map = ErrorMap::uniform(8, 8, 0);
+        let mut encoder = Encoder::new(Crossed, Regional::new(map.clone()), 2);
+        match encoder.encode(get_test_image(16, 8)) {
+            Err(Error::InvalidDimensions(_)) => {}
+            _ => panic!("image larger than the error map was encoded"),
+        }
+
+        let mut archive = get_test_archive();
+        archive.metadata.error_map = Some(map);
+        match ArchiveWriter::new(Vec::new(), &archive.metadata) {
+            Err(Error::InvalidDimensions(_)) => {}
+            _ => panic!("error map of another image was written"),
+        }
+
+        // a map claiming 2^32 blocks is rejected before they are built
+        archive.metadata.error_map = None;
+        let runs = (0u8, 1u32 << 16, 1u32 << 16, vec![(0u8, u32::MAX), (0u8, 1u32)]);
+        let mut buffer = Vec::new();
+        archive.serialize_to_writer(&mut buffer).unwrap();
+        let end = buffer.split_off(buffer.len() - 12);
+        archive::write_chunk(&mut buffer, archive::ERROR_MAP, &bincode::serialize(&runs).unwrap()).unwrap();
+        buffer.extend(end);
+        assert_corrupted(&buffer);
+    }
+
+    #[test]
+    fn legacy_error_map() {
+        let mut archive = get_test_archive();
+        let map = ErrorMap::uniform(archive.metadata.width, archive.metadata.height, 5);
+        archive.metadata.quantization_level = QuantizationLevel::Lossless;
+
+        // readers unaware of the map must not take such an archive for a lossless one
+        assert!(archive::is_critical(archive::ERROR_MAP));
+        let mut chunks = Vec::new();
+        archive.serialize_to_writer(&mut chunks).unwrap();
+        let mut buffer = Vec::new();
+        archive::write_header(&mut buffer, &archive.metadata).unwrap();
+        let chunks = chunks.split_off(buffer.len());
+        archive::write_chunk(&mut buffer, archive::LEGACY_ERROR_MAP, &bincode::serialize(&map).unwrap()).unwrap();
+        buffer.extend(chunks);
+
+        let reader = ArchiveReader::new(&buffer[..]).unwrap();
+        assert_eq!(reader.metadata().error_map, Some(map));
+        assert_eq!(reader.metadata().max_error(), 5);
+    }
+
     #[test]
     fn serde() {
         let levels = 3;
@@ -105,7 +287,7 @@ mod tests {
         let quantization_level = QuantizationLevel::Lossless;
         let quantizator = Linear::from(quantization_level);
         let mut encoder = Encoder::new(interpolator, quantizator, levels);
-        let grid = encoder.encode(image);
+        let grid = encoder.encode(image).unwrap();
 
         let metadata = Metadata {
             quantization_level,
@@ -113,6 +295,8 @@ mod tests {
             width,
             height,
             scale_level: levels,
+            error_map: None,
+            tiling: None,
         };
         let archive = Archive { metadata, grid };
         let mut buffer = Vec::new();
@@ -123,4 +307,948 @@ mod tests {
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), archive);
     }
+
+    fn get_test_archive() -> Archive<Grid> {
+        let levels = 2;
+        let (width, height) = (10, 6);
+        let quantization_level = QuantizationLevel::Low;
+        let mut encoder = Encoder::new(Crossed, Linear::from(quantization_level), levels);
+        let grid = encoder.encode(get_test_image(width, height)).unwrap();
+        let metadata = Metadata {
+            quantization_level,
+            interpolation: InterpolationType::Crossed,
+            width,
+            height,
+            scale_level: levels,
+            error_map: None,
+            tiling: None,
+        };
+        Archive { metadata, grid }
+    }
+
+    #[test]
+    fn deserialize_v0() {
+        let archive = get_test_archive();
+        let metadata = &archive.metadata;
+
+        let mut buffer = Vec::new();
+        buffer.write_u32::<LE>(archive::MAGIC_V0).unwrap();
+        let header = (
+            metadata.quantization_level,
+            metadata.interpolation.clone(),
+            metadata.width,
+            metadata.height,
+            metadata.scale_level,
+        );
+        bincode::serialize_into(&mut buffer, &header).unwrap();
+        let mut encoder = DeflateEncoder::new(buffer, Compression::best());
+        encoder.write_all(&bincode::serialize(&archive.grid).unwrap()).unwrap();
+        let buffer = encoder.finish().unwrap();
+
+        let res = Archive::deserialize_from_reader(&mut io::Cursor::new(&buffer));
+        assert_eq!(res.unwrap(), archive);
+        assert!(!ArchiveReader::new(&buffer[..]).unwrap().has_checksums());
+    }
+
+    // Serialized archive with `tag` chunk inserted before the end chunk
+    fn serialize_with_chunk(archive: &Archive<Grid>, tag: archive::Tag) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        archive.serialize_to_writer(&mut buffer).unwrap();
+        let end = buffer.split_off(buffer.len() - 12);
+        archive::write_chunk(&mut buffer, tag, b"unknown chunk").unwrap();
+        buffer.extend(end);
+        buffer
+    }
+
+    #[test]
+    fn skip_ancillary_chunk() {
+        let archive = get_test_archive();
+        let buffer = serialize_with_chunk(&archive, *b"tEXt");
+        let res = Archive::deserialize_from_reader(&mut io::Cursor::new(&buffer));
+        assert_eq!(res.unwrap(), archive);
+    }
+
+    #[test]
+    fn reject_critical_chunk() {
+        let archive = get_test_archive();
+        let buffer = serialize_with_chunk(&archive, *b"TILE");
+        let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(&buffer));
+        match res {
+            Err(Error::UnsupportedChunk(tag)) => assert_eq!(&tag, b"TILE"),
+            _ => panic!("unknown critical chunk was accepted"),
+        }
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let archive = get_test_archive();
+        let mut buffer = Vec::new();
+        archive.serialize_to_writer(&mut buffer).unwrap();
+        buffer[4] = archive::FORMAT_VERSION as u8 + 1;
+        let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(&buffer));
+        match res {
+            Err(Error::UnsupportedVersion(version)) => assert_eq!(version, archive::FORMAT_VERSION + 1),
+            _ => panic!("newer version was accepted"),
+        }
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(b"\x89PNG\r\n\x1a\n"));
+        match res {
+            Err(Error::BadMagic(magic)) => assert_eq!(magic, 0x474E_5089),
+            _ => panic!("foreign data was accepted"),
+        }
+    }
+
+    fn assert_corrupted(buffer: &[u8]) {
+        let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(buffer));
+        match res.expect_err("corrupted archive was accepted") {
+            Error::Corrupted(_) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn detect_bit_flips() {
+        let archive = get_test_archive();
+        let mut buffer = Vec::new();
+        archive.serialize_to_writer(&mut buffer).unwrap();
+
+        // every bit after the magic and version
+        for byte in 6..buffer.len() {
+            for bit in 0..8 {
+                let mut corrupted = buffer.clone();
+                corrupted[byte] ^= 1 << bit;
+                // a longer chunk length runs past the end
+                let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(&corrupted));
+                match res.expect_err("corrupted archive was accepted") {
+                    Error::Corrupted(_) | Error::Truncated => {}
+                    error => panic!("unexpected error: {}", error),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detect_truncation() {
+        let archive = get_test_archive();
+        let mut buffer = Vec::new();
+        archive.serialize_to_writer(&mut buffer).unwrap();
+
+        for length in 6..buffer.len() {
+            let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(&buffer[..length]));
+            match res.expect_err("truncated archive was accepted") {
+                Error::Truncated => {}
+                error => panic!("unexpected error: {}", error),
+            }
+        }
+    }
+
+    #[test]
+    fn tiling() {
+        let tiling = Tiling::new(16, 8).unwrap();
+        let tiles: Vec<_> = tiling.tiles((40, 20)).collect();
+        assert_eq!(tiles.len(), 9);
+        assert_eq!(tiles[0], Rect::new(0, 0, 16, 8));
+        assert_eq!(tiles[2], Rect::new(32, 0, 8, 8));
+        assert_eq!(tiles[8], Rect::new(32, 16, 8, 4));
+    }
+
+    #[test]
+    fn tiled_archive() {
+        let levels = 3;
+        let (width, height) = (45, 30);
+        let image = get_test_image(width, height);
+        let mut mask = GrayImage::new(width, height);
+        for (x, _, pixel) in mask.enumerate_pixels_mut() {
+            *pixel = Luma([if x >= 24 { 255 } else { 0 }]);
+        }
+        let map = ErrorMap::from_mask(&mask, 3, 0, QuantizationLevel::Medium.error()).unwrap();
+
+        let metadata = Metadata {
+            quantization_level: QuantizationLevel::Medium,
+            interpolation: InterpolationType::Crossed,
+            width,
+            height,
+            scale_level: levels,
+            error_map: Some(map.clone()),
+            tiling: Some(Tiling::new(16, 16).unwrap()),
+        };
+        let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+        let mut encoder = Encoder::new(Crossed, Regional::new(map.clone()), levels);
+        encoder.encode_tiles(&image, &mut writer).unwrap();
+        let buffer = writer.finish().unwrap();
+
+        let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+        assert_eq!(reader.metadata(), &metadata);
+        let decoded = Decoder::new(Crossed).decode_tiles(&mut reader).unwrap();
+        assert_eq!(reader.finish().unwrap(), metadata);
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let diff = (i32::from(pixel.data[0]) - i32::from(decoded[(x, y)].data[0])).abs();
+            assert!(diff <= i32::from(map.error_at((x, y))));
+        }
+
+        // tiled archives hold no single grid
+        let res = Archive::<Grid>::deserialize_from_reader(&mut io::Cursor::new(&buffer));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn reject_tile_count_overflow() {
+        let metadata = Metadata {
+            quantization_level: QuantizationLevel::Lossless,
+            interpolation: InterpolationType::Crossed,
+            width: u32::MAX,
+            height: u32::MAX,
+            scale_level: 1,
+            error_map: None,
+            tiling: Some(Tiling::new(1, 1).unwrap()),
+        };
+        let mut buffer = Vec::new();
+        archive::write_header(&mut buffer, &metadata).unwrap();
+        archive::write_chunk(&mut buffer, archive::END, &[]).unwrap();
+
+        assert_corrupted(&buffer);
+        match Archive::read_metadata(&buffer[..]) {
+            Err(Error::Corrupted(_)) => {}
+            _ => panic!("archive with too many tiles was read"),
+        }
+        assert!(decode_from_reader(&buffer[..]).is_err());
+        match ArchiveWriter::new(Vec::new(), &metadata) {
+            Err(Error::InvalidDimensions(_)) => {}
+            _ => panic!("archive with too many tiles was written"),
+        }
+    }
+
+    #[test]
+    fn reject_oversized_image() {
+        let metadata = Metadata {
+            width: u32::MAX,
+            height: u32::MAX,
+            scale_level: 1,
+            tiling: None,
+            ..get_test_archive().metadata
+        };
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&[0; 4]).unwrap();
+        let level = encoder.finish().unwrap();
+        let mut buffer = Vec::new();
+        archive::write_header(&mut buffer, &metadata).unwrap();
+        archive::write_chunk(&mut buffer, archive::LEVEL, &level).unwrap();
+        archive::write_chunk(&mut buffer, archive::END, &[]).unwrap();
+
+        assert_corrupted(&buffer);
+        assert!(decode_from_reader(&buffer[..]).is_err());
+        match ArchiveWriter::new(Vec::new(), &metadata) {
+            Err(Error::InvalidDimensions(_)) => {}
+            _ => panic!("archive of a too large image was written"),
+        }
+
+        // fits into memory, but not into the first level chunk
+        let metadata = Metadata { width: 1 << 16, height: 1 << 16, ..metadata };
+        let mut buffer = Vec::new();
+        archive::write_header(&mut buffer, &metadata).unwrap();
+        archive::write_chunk(&mut buffer, archive::LEVEL, &level).unwrap();
+        archive::write_chunk(&mut buffer, archive::END, &[]).unwrap();
+        assert_corrupted(&buffer);
+    }
+
+    #[test]
+    fn reject_oversized_levels() {
+        let metadata = Metadata {
+            width: 60000,
+            height: 60000,
+            scale_level: 1,
+            tiling: None,
+            ..get_test_archive().metadata
+        };
+        let mut buffer = Vec::new();
+        archive::write_header(&mut buffer, &metadata).unwrap();
+        for _ in 0..2 {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&[0; 4]).unwrap();
+            archive::write_chunk(&mut buffer, archive::LEVEL, &encoder.finish().unwrap()).unwrap();
+        }
+        archive::write_chunk(&mut buffer, archive::END, &[]).unwrap();
+
+        assert_corrupted(&buffer);
+        let tile = ArchiveReader::new(&buffer[..]).and_then(|mut reader| reader.next_tile());
+        match tile {
+            Err(Error::Corrupted(_)) => {}
+            _ => panic!("levels of a smaller image were read"),
+        }
+    }
+
+    #[test]
+    fn missing_tiles() {
+        let metadata = Metadata {
+            tiling: Some(Tiling::new(4, 4).unwrap()),
+            ..get_test_archive().metadata
+        };
+        let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+        assert!(writer.write_tile(&get_test_archive().grid).is_err());
+        writer.write_tile(&Grid::new(4, 4)).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn decode_region() {
+        let levels = 3;
+        let (width, height) = (61, 45);
+        let mut image = GrayImage::new(width, height);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Luma([((x * 37) ^ (y * 11) ^ (x * y)) as u8]);
+        }
+
+        for &tiling in &[None, Some(Tiling::new(16, 16).unwrap()), Some(Tiling::new(20, 12).unwrap())] {
+            let metadata = Metadata {
+                quantization_level: QuantizationLevel::Low,
+                interpolation: InterpolationType::Crossed,
+                width,
+                height,
+                scale_level: levels,
+                error_map: None,
+                tiling,
+            };
+            let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+            let mut encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+            encoder.encode_tiles(&image, &mut writer).unwrap();
+            let buffer = writer.finish().unwrap();
+
+            if tiling.is_none() {
+                let mut reader = ArchiveReader::new(io::Cursor::new(&buffer)).unwrap();
+                match Decoder::new(Crossed).decode_region(&mut reader, Rect::new(0, 0, 1, 1)) {
+                    Err(Error::Unsupported(_)) => continue,
+                    _ => panic!("region of an untiled archive was decoded"),
+                }
+            }
+
+            let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+            let decoded = Decoder::new(Crossed).decode_tiles(&mut reader).unwrap();
+
+            for &(x, y) in &[(0, 0), (1, 3), (9, 17), (30, 8), (50, 40)] {
+                for &(w, h) in &[(1, 1), (5, 2), (11, 13), (40, 30)] {
+                    let rect = Rect::new(x, y, w.min(width - x), h.min(height - y));
+                    let mut reader = ArchiveReader::new(io::Cursor::new(&buffer)).unwrap();
+                    let region = Decoder::new(Crossed).decode_region(&mut reader, rect).unwrap();
+                    for (column, line, pixel) in region.enumerate_pixels() {
+                        assert_eq!(*pixel, decoded[(x + column, y + line)], "{:?} {:?}", tiling, rect);
+                    }
+                }
+            }
+
+            for &rect in &[Rect::new(50, 40, 20, 1), Rect::new(50, 40, u32::MAX, 1), Rect::new(1, u32::MAX, 1, 2)] {
+                let mut reader = ArchiveReader::new(io::Cursor::new(&buffer)).unwrap();
+                assert!(Decoder::new(Crossed).decode_region(&mut reader, rect).is_err());
+            }
+        }
+
+        let rect = Rect::new(u32::MAX - 1, 0, 10, 10);
+        assert_eq!(rect.intersection(&Rect::new(0, 0, u32::MAX, 5)), Some(Rect::new(u32::MAX - 1, 0, 1, 5)));
+    }
+
+    #[test]
+    fn grid_levels() {
+        let archive = get_test_archive();
+        let levels = archive.metadata.scale_level;
+        let (width, height) = archive.grid.dimensions();
+        let data = archive.grid.levels(levels);
+        assert_eq!(data.len(), levels + 1);
+        assert_eq!(data.iter().map(Vec::len).sum::<usize>(), width * height);
+        assert_eq!(Grid::from_levels(width, height, levels, &data), Some(archive.grid));
+        assert_eq!(Grid::from_levels(width, height, levels, &data[..levels]), None);
+        assert_eq!(Grid::from_levels(width + 1, height, levels, &data), None);
+        assert_eq!(Grid::from_levels(60000, 60000, levels, &data), None);
+        assert_eq!(Grid::from_levels(usize::MAX, usize::MAX, levels, &data), None);
+        assert_eq!(Grid::from_levels(width, height, usize::MAX, &data), None);
+    }
+
+    #[test]
+    fn grid_bounds() {
+        let mut grid = Grid::from_vec(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(grid.dimensions(), (3, 2));
+        assert_eq!(grid.get(2, 1), 6);
+        grid.set((0, 1), 9);
+        assert_eq!(grid.get(0, 1), 9);
+        assert_eq!(Grid::from_vec(3, 2, vec![0; 5]), None);
+        assert!(panic::catch_unwind(|| grid.get(3, 0)).is_err());
+        assert!(panic::catch_unwind(|| Grid::new(3, 2).get(0, 2)).is_err());
+
+        let data = bincode::serialize(&grid).unwrap();
+        assert_eq!(bincode::deserialize::<Grid>(&data).unwrap(), grid);
+
+        // a buffer that doesn't fill its last line
+        let data = bincode::serialize(&(vec![0u8; 5], 3usize)).unwrap();
+        assert!(bincode::deserialize::<Grid>(&data).is_err());
+    }
+
+    #[test]
+    fn reject_wrong_dimensions() {
+        let grid = Grid::new(8, 8);
+        match Decoder::new(Crossed).decode((16, 16), 2, &grid) {
+            Err(Error::InvalidDimensions(_)) => {}
+            _ => panic!("grid of another image was decoded"),
+        }
+        let rect = Rect::new(4, 4, 8, 8);
+        match Decoder::new(Crossed).decode_rect((8, 8), 2, &grid, rect) {
+            Err(Error::InvalidDimensions(_)) => {}
+            _ => panic!("region out of the image was decoded"),
+        }
+        match Encoder::new(Crossed, Linear::new(0).unwrap(), 32).encode(get_test_image(8, 8)) {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("image was encoded with too many levels"),
+        }
+    }
+
+    fn get_level_test_image() -> GrayImage {
+        let mut image = GrayImage::new(61, 45);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Luma([((x * 37) ^ (y * 11) ^ (x * y)) as u8]);
+        }
+        image
+    }
+
+    fn encode_levels(image: &GrayImage, levels: usize, tiling: Option<Tiling>) -> (Metadata, Vec<u8>) {
+        let metadata = Metadata {
+            quantization_level: QuantizationLevel::Low,
+            interpolation: InterpolationType::Crossed,
+            width: image.width(),
+            height: image.height(),
+            scale_level: levels,
+            error_map: None,
+            tiling,
+        };
+        let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+        let mut encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+        encoder.encode_tiles(image, &mut writer).unwrap();
+        (metadata, writer.finish().unwrap())
+    }
+
+    #[test]
+    fn decode_to_level() {
+        let levels = 3;
+        let image = get_level_test_image();
+        for &tiling in &[None, Some(Tiling::new(16, 16).unwrap())] {
+            let (_, buffer) = encode_levels(&image, levels, tiling);
+            let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+            let decoded = Decoder::new(Crossed).decode_tiles(&mut reader).unwrap();
+
+            for level in 0..=levels {
+                let factor = 1 << (levels - level);
+                let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+                let scaled = Decoder::new(Crossed).decode_to_level(&mut reader, level).unwrap();
+                assert_eq!(scaled.dimensions(), (61u32.div_ceil(factor), 45u32.div_ceil(factor)));
+                for (x, y, pixel) in scaled.enumerate_pixels() {
+                    assert_eq!(*pixel, decoded[(x * factor, y * factor)], "{:?} {}", tiling, level);
+                }
+                reader.finish().unwrap();
+            }
+
+            let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+            assert!(Decoder::new(Crossed).decode_to_level(&mut reader, levels + 1).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_preview() {
+        let levels = 3;
+        let image = get_level_test_image();
+        let (_, buffer) = encode_levels(&image, levels, None);
+
+        let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+        let (preview, level) = Decoder::new(Crossed).decode_preview(&mut reader).unwrap();
+        assert_eq!(level, levels);
+        let decoded = Decoder::new(Crossed).decode_tiles(&mut ArchiveReader::new(&buffer[..]).unwrap()).unwrap();
+        assert_eq!(preview.into_raw(), decoded.into_raw());
+
+        let mut previews = 0;
+        for length in (0..buffer.len()).step_by(7) {
+            let mut reader = match ArchiveReader::new(&buffer[..length]) {
+                Ok(reader) => reader,
+                Err(_) => continue,
+            };
+            let (preview, level) = match Decoder::new(Crossed).decode_preview(&mut reader) {
+                Ok(preview) => preview,
+                Err(_) => continue,
+            };
+            let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+            let scaled = Decoder::new(Crossed).decode_to_level(&mut reader, level).unwrap();
+            assert_eq!(preview.dimensions(), scaled.dimensions());
+            assert_eq!(preview.into_raw(), scaled.into_raw());
+            previews += 1;
+        }
+        assert!(previews > 0);
+    }
+
+    #[test]
+    fn thumbnail_level() {
+        let metadata = Metadata {
+            width: 1000,
+            height: 600,
+            scale_level: 4,
+            ..get_test_archive().metadata
+        };
+        assert_eq!(metadata.level_dimensions(4), (1000, 600));
+        assert_eq!(metadata.level_dimensions(0), (63, 38));
+        assert_eq!(metadata.thumbnail_level(1000), 4);
+        assert_eq!(metadata.thumbnail_level(999), 3);
+        assert_eq!(metadata.thumbnail_level(256), 2);
+        assert_eq!(metadata.thumbnail_level(63), 0);
+        assert_eq!(metadata.thumbnail_level(10), 0);
+    }
+
+    #[test]
+    fn quality_layers() {
+        let levels = 3;
+        let image = get_level_test_image();
+        let metadata = Metadata {
+            quantization_level: QuantizationLevel::High,
+            interpolation: InterpolationType::Crossed,
+            width: image.width(),
+            height: image.height(),
+            scale_level: levels,
+            error_map: None,
+            tiling: None,
+        };
+        let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+        let mut encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+        encoder.encode_layers(&image, &[12, 3, 0], &mut writer).unwrap();
+        let buffer = writer.finish().unwrap();
+
+        let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+        let (decoded, max_error) = Decoder::new(Crossed).decode_layers(&mut reader).unwrap();
+        assert_eq!(max_error, 0);
+        assert_eq!(decoded.into_raw(), image.clone().into_raw());
+        assert_eq!(Archive::deserialize_from_reader(&mut &buffer[..]).unwrap().metadata, metadata);
+
+        // every cut after the base grid decodes within the reported error
+        let mut errors = Vec::new();
+        for length in (0..buffer.len()).step_by(5) {
+            let mut reader = match ArchiveReader::new(&buffer[..length]) {
+                Ok(reader) => reader,
+                Err(_) => continue,
+            };
+            let (decoded, max_error) = match Decoder::new(Crossed).decode_layers(&mut reader) {
+                Ok(decoded) => decoded,
+                Err(_) => continue,
+            };
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let diff = (i32::from(pixel.data[0]) - i32::from(decoded[(x, y)].data[0])).abs();
+                assert!(diff <= i32::from(max_error), "{} {}", length, max_error);
+            }
+            if errors.last() != Some(&max_error) {
+                errors.push(max_error);
+            }
+        }
+        assert_eq!(errors, vec![30, 12, 3, 0]);
+
+        // a damaged layer is an error rather than the end of the refinement
+        let mut corrupted = buffer.clone();
+        let end = corrupted.len() - 12;
+        corrupted[end - 8] ^= 1;
+        let mut reader = ArchiveReader::new(&corrupted[..]).unwrap();
+        match Decoder::new(Crossed).decode_layers(&mut reader) {
+            Err(Error::Corrupted(_)) => {}
+            _ => panic!("corrupted layer was skipped"),
+        }
+
+        // the strict decoding counts the layers and refuses a cut
+        let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+        let (_, max_error, layers) = Decoder::new(Crossed).decode_layers_strict(&mut reader).unwrap();
+        assert_eq!((max_error, layers), (0, 3));
+        let mut reader = ArchiveReader::new(&buffer[..buffer.len() - 12]).unwrap();
+        match Decoder::new(Crossed).decode_layers_strict(&mut reader) {
+            Err(Error::Truncated) => {}
+            _ => panic!("truncated archive was decoded"),
+        }
+    }
+
+    #[test]
+    fn reject_layers() {
+        let archive = get_test_archive();
+        let pixels = (archive.metadata.width * archive.metadata.height) as usize;
+        let layer = |max_error| Layer { max_error, residues: vec![0; pixels] };
+
+        let mut writer = ArchiveWriter::new(Vec::new(), &archive.metadata).unwrap();
+        assert!(writer.write_layer(&layer(5)).is_err());
+        writer.write_tile(&archive.grid).unwrap();
+        assert!(writer.write_layer(&layer(10)).is_err());
+        assert!(writer.write_layer(&Layer { max_error: 5, residues: vec![0; pixels - 1] }).is_err());
+        writer.write_layer(&layer(5)).unwrap();
+        assert!(writer.write_layer(&layer(5)).is_err());
+
+        let metadata = Metadata {
+            tiling: Some(Tiling::new(16, 16).unwrap()),
+            ..archive.metadata
+        };
+        let mut writer = ArchiveWriter::new(Vec::new(), &metadata).unwrap();
+        writer.write_tile(&archive.grid).unwrap();
+        assert!(writer.write_layer(&layer(5)).is_err());
+    }
+
+    #[test]
+    fn stream_encoder() {
+        let levels = 3;
+        let image = get_level_test_image();
+        let (width, height) = image.dimensions();
+        let (mut metadata, _) = encode_levels(&image, levels, None);
+
+        for &(chunk, band) in &[(1, 8), (7, 8), (8, 8), (45, 8), (7, 16), (7, 0)] {
+            metadata.tiling = match band {
+                0 => None,
+                band => Some(Tiling::new(width, band).unwrap()),
+            };
+            let encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+            let mut stream = StreamEncoder::new(encoder, Vec::new(), metadata.clone()).unwrap();
+            for rows in image.chunks(chunk * width as usize) {
+                stream.push_rows(rows).unwrap();
+            }
+            assert_eq!(stream.rows(), height);
+            let buffer = stream.finish().unwrap();
+
+            // same as encoding the whole image in bands
+            let band = if band == 0 { DEFAULT_BAND_HEIGHT } else { band };
+            let (_, expected) = encode_levels(&image, levels, Some(Tiling::new(width, band).unwrap()));
+            assert_eq!(buffer, expected);
+        }
+
+        metadata.tiling = Some(Tiling::new(16, 16).unwrap());
+        let encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+        assert!(StreamEncoder::new(encoder, Vec::new(), metadata.clone()).is_err());
+
+        metadata.tiling = Some(Tiling::new(width, 8).unwrap());
+        let encoder = Encoder::new(Crossed, Linear::from(metadata.quantization_level), levels);
+        let mut stream = StreamEncoder::new(encoder, Vec::new(), metadata).unwrap();
+        let pixels: &[u8] = &image;
+        assert!(stream.push_rows(&pixels[..width as usize + 1]).is_err());
+        stream.push_rows(&pixels[..width as usize * 10]).unwrap();
+        assert!(stream.push_rows(pixels).is_err());
+        assert!(stream.finish().is_err());
+    }
+
+    #[test]
+    fn stream_decoder() {
+        let levels = 3;
+        let image = get_level_test_image();
+        let (width, height) = image.dimensions();
+
+        for &tiling in &[None, Some(Tiling::new(width, 8).unwrap()), Some(Tiling::new(16, 16).unwrap())] {
+            let (metadata, buffer) = encode_levels(&image, levels, tiling);
+            let decoded = Decoder::new(Crossed).decode_tiles(&mut ArchiveReader::new(&buffer[..]).unwrap()).unwrap();
+
+            let mut stream = StreamDecoder::new(Decoder::new(Crossed), &buffer[..]).unwrap();
+            let mut next_line = 0;
+            while let Some((line, band)) = stream.next_band().unwrap() {
+                assert_eq!(line, next_line);
+                assert_eq!(band.width(), width);
+                assert!(band.height() <= tiling.map_or(height, |tiling| tiling.tile_height));
+                for (x, y, pixel) in band.enumerate_pixels() {
+                    assert_eq!(*pixel, decoded[(x, line + y)]);
+                }
+                next_line += band.height();
+            }
+            assert_eq!(next_line, height);
+            assert_eq!(stream.finish().unwrap(), metadata);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_tiles() {
+        let levels = 3;
+        let image = get_level_test_image();
+        let (_, expected) = encode_levels(&image, levels, Some(Tiling::new(16, 8).unwrap()));
+        let decoded = Decoder::new(Crossed).decode_tiles(&mut ArchiveReader::new(&expected[..]).unwrap()).unwrap();
+
+        for threads in 1..=4 {
+            let pool = ::rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let (_, buffer) = pool.install(|| encode_levels(&image, levels, Some(Tiling::new(16, 8).unwrap())));
+            assert_eq!(buffer, expected);
+
+            let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+            let image = pool.install(|| Decoder::new(Crossed).decode_tiles(&mut reader).unwrap());
+            assert_eq!(image.into_raw(), decoded.clone().into_raw());
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_levels() {
+        let levels = 3;
+        let image = get_test_image(200, 150);
+        let encode = |threads| {
+            let pool = ::rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let grid = Encoder::new(Crossed, Linear::new(5).unwrap(), levels).encode(image.clone()).unwrap();
+                let decoded = Decoder::new(Crossed).decode((200, 150), levels, &grid).unwrap();
+                (grid, decoded.into_raw())
+            })
+        };
+
+        let expected = encode(1);
+        for threads in 2..=4 {
+            assert_eq!(encode(threads), expected);
+        }
+    }
+
+    fn noise(length: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simd_predictions() {
+        for &length in &[0, 1, 15, 16, 17, 31, 32, 33, 100, 257] {
+            let (upper, lower) = (noise(length + 1, 1), noise(length + 1, 2));
+            let mut expected = vec![0; length];
+            simd::predict_cells_scalar(&upper, &lower, &mut expected);
+
+            let mut predictions = vec![0; length];
+            simd::predict_cells(&upper, &lower, &mut predictions);
+            assert_eq!(predictions, expected);
+
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            {
+                if is_x86_feature_detected!("sse2") {
+                    let mut predictions = vec![0; length];
+                    unsafe { simd::predict_cells_sse2(&upper, &lower, &mut predictions) };
+                    assert_eq!(predictions, expected);
+                }
+                if is_x86_feature_detected!("avx2") {
+                    let mut predictions = vec![0; length];
+                    unsafe { simd::predict_cells_avx2(&upper, &lower, &mut predictions) };
+                    assert_eq!(predictions, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn segment_order() {
+        for &(width, height, levels) in &[(12, 8, 3), (37, 23, 4), (64, 64, 2)] {
+            for level in 0..levels {
+                let mut expected = Vec::new();
+                utils::traverse_level(level, levels, 0, width, 0, height, |x, y| expected.push((x, y)));
+
+                let mut nodes = Vec::new();
+                utils::traverse_segments(level, levels, 0, width, 0, height, |segment| {
+                    nodes.extend(segment.columns().map(|column| (column, segment.line)));
+                });
+                assert_eq!(nodes, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn segment_interpolation() {
+        for &(width, height, levels) in &[(12, 8, 3), (37, 23, 4), (130, 67, 5)] {
+            let image = GrayImage::from_raw(width, height, noise((width * height) as usize, width)).unwrap();
+            for level in 0..levels {
+                utils::traverse_segments(level, levels, 0, width, 0, height, |segment| {
+                    let expected: Vec<u8> = segment
+                        .columns()
+                        .map(|column| Crossed.interpolate(levels, level + 1, (column, segment.line), &image))
+                        .collect();
+                    let mut predictions = vec![0; segment.count as usize];
+                    Crossed.interpolate_segment(levels, level + 1, segment, &image, &mut predictions);
+                    assert_eq!(predictions, expected);
+
+                    let actual = noise(predictions.len(), segment.line);
+                    for quantizator in &[Linear::new(0).unwrap(), Linear::new(7).unwrap()] {
+                        let expected: Vec<u8> = segment
+                            .columns()
+                            .zip(predictions.iter().zip(&actual))
+                            .map(|(column, (&prediction, &actual))| {
+                                quantizator.quantize((column, segment.line), prediction, actual)
+                            })
+                            .collect();
+                        let mut residues = vec![0; predictions.len()];
+                        quantizator.quantize_segment((0, 0), segment, &predictions, &actual, &mut residues);
+                        assert_eq!(residues, expected);
+                    }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn encoder_config() {
+        let image = get_test_image(45, 30);
+        let configs = [
+            (EncoderConfig::default(), 20),
+            (EncoderConfig::default().max_error(5).levels(3), 5),
+            (EncoderConfig::default().max_error(0).coding(Coding::Tiled(16)), 0),
+            (EncoderConfig::default().coding(Coding::Layered(vec![7, 0])), 0),
+            (EncoderConfig::default().max_error(3).interpolation(InterpolationType::Previous), 3),
+        ];
+
+        for &(ref config, max_error) in &configs {
+            let input = DynamicImage::ImageLuma8(image.clone());
+            let buffer = config.encode_to_writer(&input, Vec::new()).unwrap();
+            let decoded = decode_from_reader(&buffer[..]).unwrap().to_luma();
+            assert_eq!(decoded.dimensions(), image.dimensions());
+            for (decoded, pixel) in decoded.pixels().zip(image.pixels()) {
+                let error = (i16::from(decoded.data[0]) - i16::from(pixel.data[0])).abs();
+                assert!(error <= max_error, "{:?}", config);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_encoder_config() {
+        let image = DynamicImage::ImageLuma8(get_test_image(8, 8));
+        let encode = |config: EncoderConfig, image: &DynamicImage| config.encode_to_writer(image, Vec::new());
+
+        let color = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+        match encode(EncoderConfig::default(), &color) {
+            Err(Error::UnsupportedColorType(_)) => {}
+            _ => panic!("color image was encoded"),
+        }
+        match encode(EncoderConfig::default().interpolation(InterpolationType::Line), &image) {
+            Err(Error::UnsupportedInterpolation(InterpolationType::Line)) => {}
+            _ => panic!("unimplemented interpolation was used"),
+        }
+        assert!(encode(EncoderConfig::default().max_error(quantizator::MAX_ERROR + 1), &image).is_err());
+        assert!(encode(EncoderConfig::default().coding(Coding::Tiled(0)), &image).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_arguments() {
+        let invalid = |res: Result<(), Error>| match res {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("invalid argument was accepted"),
+        };
+        invalid(Linear::new(quantizator::MAX_ERROR + 1).map(|_| ()));
+        invalid(Tiling::new(0, 8).map(|_| ()));
+
+        // the tiling fields are public, so a zero size may still reach the writers
+        let mut archive = get_test_archive();
+        archive.metadata.tiling = Some(Tiling { tile_width: 16, tile_height: 0 });
+        invalid(ArchiveWriter::new(Vec::new(), &archive.metadata).map(|_| ()));
+        let encoder = Encoder::new(Crossed, Linear::from(QuantizationLevel::Low), archive.metadata.scale_level);
+        invalid(StreamEncoder::new(encoder, Vec::new(), archive.metadata.clone()).map(|_| ()));
+
+        // and a reader takes it for a damaged archive
+        let tiling = archive.metadata.tiling.take().unwrap();
+        let mut buffer = Vec::new();
+        archive::write_header(&mut buffer, &archive.metadata).unwrap();
+        archive::write_chunk(&mut buffer, archive::TILING, &bincode::serialize(&tiling).unwrap()).unwrap();
+        archive::write_chunk(&mut buffer, archive::END, &[]).unwrap();
+        assert_corrupted(&buffer);
+
+        let (width, height) = (archive.metadata.width, archive.metadata.height);
+        let mut writer = ArchiveWriter::new(Vec::new(), &archive.metadata).unwrap();
+        let mut encoder = Encoder::new(Crossed, Linear::from(QuantizationLevel::Low), archive.metadata.scale_level);
+        invalid(encoder.encode_layers(&get_test_image(width, height), &[quantizator::MAX_ERROR + 1], &mut writer));
+    }
+
+    #[test]
+    fn image_codec() {
+        let (width, height) = (37, 21);
+        let image = get_test_image(width, height);
+        let mut buffer = Vec::new();
+        let config = EncoderConfig::default().max_error(0);
+        HgiEncoder::with_config(&mut buffer, config)
+            .encode(&image, width, height, ColorType::Gray(8))
+            .unwrap();
+        assert!(HgiEncoder::new(Vec::new()).encode(&image, width, height, ColorType::RGB(8)).is_err());
+        assert!(HgiEncoder::new(Vec::new()).encode(&image, width + 1, height, ColorType::Gray(8)).is_err());
+
+        let mut decoder = HgiDecoder::new(&buffer[..]).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (width, height));
+        assert_eq!(decoder.colortype().unwrap(), ColorType::Gray(8));
+        assert_eq!(decoder.row_len().unwrap(), width as usize);
+        let mut line = vec![0; width as usize];
+        for row in 0..height {
+            assert_eq!(decoder.read_scanline(&mut line).unwrap(), row + 1);
+            assert_eq!(&line[..], &image.as_ref()[(row * width) as usize..][..width as usize]);
+        }
+        assert!(decoder.read_scanline(&mut line).is_err());
+
+        match HgiDecoder::new(&buffer[..]).unwrap().read_image().unwrap() {
+            DecodingResult::U8(data) => assert_eq!(data, image.clone().into_raw()),
+            _ => panic!("16 bit image decoded"),
+        }
+
+        // archives and other formats open the same way
+        let directory = ::std::env::temp_dir();
+        let archive_path = directory.join("hgi_image_codec.hgi");
+        let png_path = directory.join("hgi_image_codec.png");
+        ::std::fs::write(&archive_path, &buffer).unwrap();
+        image.save(&png_path).unwrap();
+        for path in &[archive_path, png_path] {
+            let opened = codec::open(path).unwrap().to_luma();
+            let loaded = codec::load_from_memory(&::std::fs::read(path).unwrap()).unwrap().to_luma();
+            ::std::fs::remove_file(path).unwrap();
+            assert_eq!(opened.into_raw(), image.clone().into_raw());
+            assert_eq!(loaded.into_raw(), image.clone().into_raw());
+        }
+    }
+
+    #[test]
+    fn read_metadata() {
+        let image = get_level_test_image();
+        let config = EncoderConfig::default().max_error(7).levels(3).coding(Coding::Layered(vec![2]));
+        let buffer = config.encode_to_writer(&DynamicImage::ImageLuma8(image.clone()), Vec::new()).unwrap();
+
+        let metadata = Archive::read_metadata(&buffer[..]).unwrap();
+        assert_eq!((metadata.width, metadata.height), image.dimensions());
+        assert_eq!((metadata.scale_level, metadata.max_error()), (3, 7));
+
+        let reader = ArchiveReader::new(&buffer[..]).unwrap();
+        let size = reader.payload_size().unwrap();
+        assert_eq!(size.layers.len(), 1);
+        assert!(size.grid > 0 && size.grid + size.layers[0] < buffer.len() as u64);
+
+        let mut reader = ArchiveReader::new(&buffer[..]).unwrap();
+        reader.next_tile().unwrap();
+        assert_eq!(reader.payload_size().unwrap(), PayloadSize { grid: 0, layers: size.layers.clone() });
+
+        // the data is skipped by the chunk lengths, unchecked
+        let mut damaged = buffer.clone();
+        let end = damaged.len() - 12;
+        damaged[end - 8] ^= 1;
+        assert_eq!(ArchiveReader::new(&damaged[..]).unwrap().payload_size().unwrap(), size);
+        match ArchiveReader::new(&buffer[..end - 8]).unwrap().payload_size() {
+            Err(Error::Truncated) => {}
+            _ => panic!("truncated archive was measured"),
+        }
+    }
+
+    #[test]
+    fn quality_metrics() {
+        let image = get_test_image(200, 190);
+        let same = Metrics::compute(&image, &image).unwrap();
+        assert_eq!((same.mse, same.max_error), (0.0, 0));
+        assert!(same.psnr.is_infinite());
+        assert!((same.ssim - 1.0).abs() < 1e-9 && (same.ms_ssim - 1.0).abs() < 1e-9);
+
+        // shifting every pixel by 4 only leaves the luminance terms below one
+        let shifted = GrayImage::from_fn(200, 190, |x, y| Luma([image[(x, y)].data[0] / 2 + 4]));
+        let half = GrayImage::from_fn(200, 190, |x, y| Luma([image[(x, y)].data[0] / 2]));
+        let offset = Metrics::compute(&half, &shifted).unwrap();
+        assert_eq!((offset.mse, offset.max_error), (16.0, 4));
+        assert!((offset.psnr - 10.0 * (255.0f64 * 255.0 / 16.0).log10()).abs() < 1e-9);
+        assert!(offset.ssim < 1.0 && offset.ssim > 0.99);
+        assert!(offset.ms_ssim < 1.0 && offset.ms_ssim > offset.ssim);
+
+        let noisy = GrayImage::from_fn(200, 190, |x, y| Luma([image[(x, y)].data[0] ^ ((x * 7 + y * 13) % 32) as u8]));
+        let worse = Metrics::compute(&image, &noisy).unwrap();
+        assert!(worse.ssim < offset.ssim && worse.psnr < offset.psnr);
+
+        // images smaller than the window
+        let small = get_test_image(6, 4);
+        assert!((metrics::ssim(&small, &small).unwrap() - 1.0).abs() < 1e-9);
+        assert!((metrics::ms_ssim(&small, &small).unwrap() - 1.0).abs() < 1e-9);
+
+        let error = metrics::mse(&image, &small).unwrap_err();
+        assert!(matches!(error, Error::InvalidDimensions(_)), "{}", error);
+    }
 }
@@ -1,114 +1,392 @@
-#![feature(stmt_expr_attributes)]
-
-extern crate bincode;
 extern crate byteorder;
-extern crate flate2;
-extern crate image;
-extern crate serde;
 #[macro_use]
 extern crate clap;
-#[macro_use]
-extern crate structopt;
+extern crate hgi;
+extern crate image;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
+extern crate structopt;
 
 use std::error::Error;
-use std::io::{Write, BufWriter, BufReader};
+use std::io::{self, Read, Write, BufWriter, BufReader};
 use std::fs::File;
 use std::path::Path;
+use std::fs;
+use std::process;
 
 use structopt::StructOpt;
 
-mod archive;
-mod decoder;
-mod encoder;
+mod batch;
+mod bench;
 mod options;
-mod utils;
-mod grid;
-mod quantizator;
-mod interpolator;
-
-use archive::{Archive, Metadata};
-use interpolator::InterpolationType;
-use decoder::Decoder;
-use encoder::Encoder;
-use grid::Grid;
-use interpolator::Crossed;
-use quantizator::Linear;
-use options::{IO, EncodingOptions, Opts};
-
-
-fn encode(io: &IO, opts: &EncodingOptions) -> Result<(), Box<Error>> {
-    let image = image::open(&io.input)?.to_luma();
-    let quantizator = Linear::from(opts.quantization_level);
-    let interpolator = Crossed;
-    let mut encoder = Encoder::new(interpolator, quantizator, opts.level);
-    let (width, height) = image.dimensions();
-    let grid = encoder.encode(image);
+mod tiff;
+
+use hgi::{ArchiveReader, ArchiveWriter, Encoder, Metadata};
+use hgi::error_map::{ErrorMap, DEFAULT_BLOCK_SHIFT};
+use hgi::interpolator::{Crossed, InterpolationType};
+use hgi::metrics::Metrics;
+use hgi::quantizator::{Linear, Quantizator, Regional};
+use hgi::tiling::Tiling;
+use image::{ColorType, GrayImage};
+use image::png::PNGEncoder;
+use image::pnm::{PNMEncoder, PNMSubtype, SampleEncoding};
+use options::{is_stdio, IO, EncodingOptions, Format, Opts};
+
+
+fn error_map(image: &GrayImage, opts: &EncodingOptions) -> Result<Option<ErrorMap>, Box<dyn Error>> {
+    let map = if let Some(ref path) = opts.error_map {
+        ErrorMap::from_image(&hgi::open(path)?.to_luma(), DEFAULT_BLOCK_SHIFT)?
+    } else if let Some(ref path) = opts.roi {
+        let inside = opts.roi_quantization_level.error();
+        let outside = opts.quantization_level.error();
+        ErrorMap::from_mask(&hgi::open(path)?.to_luma(), DEFAULT_BLOCK_SHIFT, inside, outside)?
+    } else {
+        return Ok(None);
+    };
+
+    if map.dimensions() != image.dimensions() {
+        return Err("error map dimensions differ from the image".into());
+    }
+    Ok(Some(map))
+}
 
+fn compress<W: Write>(image: &GrayImage, opts: &EncodingOptions, w: W) -> Result<W, Box<dyn Error>> {
+    let (width, height) = image.dimensions();
     let metadata = Metadata {
         quantization_level: opts.quantization_level,
         interpolation: InterpolationType::Crossed,
         width,
         height,
         scale_level: opts.level,
+        error_map: error_map(image, opts)?,
+        tiling: opts.tile_size.map(|size| Tiling::new(size, size)).transpose()?,
     };
-    let archive = Archive { metadata, grid };
-    let mut output = BufWriter::new(File::create(&io.output)?);
-    archive.serialize_to_writer(&mut output)?;
 
+    let mut writer = ArchiveWriter::new(w, &metadata)?;
+    match metadata.error_map {
+        Some(ref map) => {
+            let encoder = Encoder::new(Crossed, Regional::new(map.clone()), opts.level);
+            encode_with(encoder, image, &opts.layers, &mut writer)?
+        }
+        None => {
+            let encoder = Encoder::new(Crossed, Linear::from(opts.quantization_level), opts.level);
+            encode_with(encoder, image, &opts.layers, &mut writer)?
+        }
+    }
+    Ok(writer.finish()?)
+}
+
+fn encode_with<Q: Quantizator, W: Write>(
+    mut encoder: Encoder<Crossed, Q>,
+    image: &GrayImage,
+    layers: &[u8],
+    writer: &mut ArchiveWriter<W>,
+) -> Result<(), hgi::Error> {
+    if layers.is_empty() {
+        encoder.encode_tiles(image, writer)
+    } else {
+        encoder.encode_layers(image, layers, writer)
+    }
+}
+
+// Returns the image refined by every complete quality layer with its maximum error
+fn decompress<R: Read>(r: R) -> Result<(GrayImage, u8), hgi::Error> {
+    let mut reader = ArchiveReader::new(r)?;
+    hgi::decode_archive(&mut reader)
+}
+
+fn open_input(path: &Path) -> io::Result<Box<dyn Read>> {
+    if is_stdio(path) {
+        Ok(Box::new(BufReader::new(io::stdin())))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+fn create_output(path: &Path) -> io::Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        Ok(Box::new(BufWriter::new(io::stdout())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+fn open_image(path: &Path) -> Result<GrayImage, Box<dyn Error>> {
+    if is_stdio(path) {
+        let mut buffer = Vec::new();
+        io::stdin().read_to_end(&mut buffer)?;
+        return Ok(hgi::load_from_memory(&buffer)?.to_luma());
+    }
+    Ok(hgi::open(path)?.to_luma())
+}
+
+// Without a format stdout gets PNG and files the format of their extension
+fn save(image: &GrayImage, path: &Path, format: Option<Format>) -> Result<(), Box<dyn Error>> {
+    let format = match format.or_else(|| Format::from_path(path)) {
+        Some(format) => format,
+        None if is_stdio(path) => Format::Png,
+        None => return Ok(image.save(path)?),
+    };
+
+    let mut output = create_output(path)?;
+    let (width, height) = image.dimensions();
+    match format {
+        Format::Png => PNGEncoder::new(&mut output).encode(image, width, height, ColorType::Gray(8))?,
+        Format::Pgm => PNMEncoder::new(&mut output)
+            .with_subtype(PNMSubtype::Graymap(SampleEncoding::Binary))
+            .encode(image.as_ref(), width, height, ColorType::Gray(8))?,
+        Format::Tiff => tiff::write_gray(&mut output, image)?,
+    }
+    output.flush()?;
     Ok(())
 }
 
-fn decode(io: &IO) -> Result<(), Box<Error>> {
-    let mut input = BufReader::new(File::open(&io.input)?);
-    let archive = Archive::<Grid>::deserialize_from_reader(&mut input)?;
-    let dimensions = (archive.metadata.width, archive.metadata.height);
-    let mut decoder = Decoder::new(Crossed);
-    let image = decoder.decode(dimensions, archive.metadata.scale_level, &archive.grid);
-    image.save(&io.output)?;
+fn encode(io: &IO, opts: &EncodingOptions) -> Result<(), Box<dyn Error>> {
+    if batch::is_batch(io) {
+        return batch::run(io, batch::IMAGE_EXTENSIONS, "hgi", |input, output| encode_file(input, output, opts));
+    }
+    encode_file(&io.input[0], &io.output, opts)
+}
+
+fn encode_file(input: &Path, output: &Path, opts: &EncodingOptions) -> Result<(), Box<dyn Error>> {
+    let image = open_image(input)?;
+    compress(&image, opts, create_output(output)?)?.flush()?;
+
+    Ok(())
+}
+
+fn decode(io: &IO, format: Option<Format>) -> Result<(), Box<dyn Error>> {
+    if batch::is_batch(io) {
+        let extension = format.map_or("png", Format::extension);
+        return batch::run(io, batch::ARCHIVE_EXTENSIONS, extension, |input, output| {
+            decode_file(input, output, format).map(|_| ())
+        });
+    }
+    let max_error = decode_file(&io.input[0], &io.output, format)?;
+    // stdout may carry the image
+    if is_stdio(&io.output) {
+        eprintln!("Maximum error: {}", max_error);
+    } else {
+        println!("Maximum error: {}", max_error);
+    }
+    Ok(())
+}
+
+// Returns the maximum error of the decoded image
+fn decode_file(input: &Path, output: &Path, format: Option<Format>) -> Result<u8, Box<dyn Error>> {
+    let (image, max_error) = decompress(open_input(input)?)?;
+    save(&image, output, format)?;
+    Ok(max_error)
+}
+
+fn thumbnail(io: &IO, max_size: u32, format: Option<Format>) -> Result<(), Box<dyn Error>> {
+    if max_size == 0 {
+        return Err("maximum size must not be zero".into());
+    }
+    if batch::is_batch(io) {
+        let extension = format.map_or("png", Format::extension);
+        return batch::run(io, batch::ARCHIVE_EXTENSIONS, extension, |input, output| {
+            thumbnail_file(input, output, max_size, format)
+        });
+    }
+    thumbnail_file(&io.input[0], &io.output, max_size, format)
+}
+
+fn thumbnail_file(input: &Path, output: &Path, max_size: u32, format: Option<Format>) -> Result<(), Box<dyn Error>> {
+    let mut reader = ArchiveReader::new(open_input(input)?)?;
+    let level = reader.metadata().thumbnail_level(max_size);
+    let mut image = hgi::decode_archive_to_level(&mut reader, level)?;
+
+    // even the first level may be too large for very big images
+    let (width, height) = image.dimensions();
+    if width > max_size || height > max_size {
+        let scale = f64::from(max_size) / f64::from(width.max(height));
+        let width = ((f64::from(width) * scale) as u32).max(1);
+        let height = ((f64::from(height) * scale) as u32).max(1);
+        image = image::imageops::thumbnail(&image, width, height);
+    }
+    save(&image, output, format)?;
     Ok(())
 }
 
-fn test(input: &Path, suffix: &str, opts: &EncodingOptions) -> Result<(), Box<Error>> {
-    let image_before = image::open(input)?.to_luma();
+#[derive(Serialize)]
+struct Info {
+    width: u32,
+    height: u32,
+    levels: usize,
+    interpolation: String,
+    quantization: String,
+    max_error: u8,
+    coding: String,
+    tiles: usize,
+    layers: usize,
+    backend: &'static str,
+    payload_size: u64,
+    archive_size: u64,
+    bits_per_pixel: f64,
+}
 
-    let quantizator = Linear::from(opts.quantization_level);
-    let interpolator = Crossed;
-    let mut encoder = Encoder::new(interpolator, quantizator, opts.level);
-    let grid = encoder.encode(image_before.clone());
+fn info(input: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    let reader = ArchiveReader::new(BufReader::new(File::open(input)?))?;
+    let metadata = reader.metadata().clone();
+    let payload = reader.payload_size()?;
+    let archive_size = fs::metadata(input)?.len();
 
-    let mut decoder = Decoder::new(Crossed);
-    let image_after = decoder.decode(image_before.dimensions(), opts.level, &grid);
+    let quantization = match metadata.error_map {
+        Some(_) => "error map".to_string(),
+        None => metadata.quantization_level.to_string(),
+    };
+    let coding = match metadata.tiling {
+        Some(tiling) => format!("tiled {}x{}", tiling.tile_width, tiling.tile_height),
+        None => "progressive".to_string(),
+    };
+    let pixels = u64::from(metadata.width) * u64::from(metadata.height);
+    let info = Info {
+        width: metadata.width,
+        height: metadata.height,
+        levels: metadata.scale_level,
+        interpolation: format!("{:?}", metadata.interpolation),
+        quantization,
+        max_error: metadata.max_error(),
+        coding,
+        tiles: metadata.tile_count(),
+        layers: payload.layers.len(),
+        backend: "deflate",
+        payload_size: payload.grid + payload.layers.iter().sum::<u64>(),
+        archive_size,
+        bits_per_pixel: if pixels > 0 { 8.0 * archive_size as f64 / pixels as f64 } else { 0.0 },
+    };
 
-    let mut sd = 0usize;
-    for (x, y, before) in image_before.enumerate_pixels() {
-        let before = before.data[0];
-        let after = image_after[(x, y)].data[0];
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    println!("Dimensions:     {}x{}", info.width, info.height);
+    println!("Levels:         {}", info.levels);
+    println!("Interpolation:  {}", info.interpolation);
+    println!("Quantization:   {}, maximum error {}", info.quantization, info.max_error);
+    println!("Coding:         {}, {} tiles, {} layers, {}", info.coding, info.tiles, info.layers, info.backend);
+    println!("Payload:        {} bytes", info.payload_size);
+    println!("Archive:        {} bytes", info.archive_size);
+    println!("Bits per pixel: {:.3}", info.bits_per_pixel);
+    Ok(())
+}
 
-        let diff = (i32::from(before) - i32::from(after)).abs() as usize;
+fn verify(archive: &Path, original: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut reader = ArchiveReader::new(BufReader::new(File::open(archive)?))?;
+    let metadata = reader.metadata().clone();
+    let checksums = reader.has_checksums();
+    let (decoded, max_error, layers) = hgi::decode_archive_strict(&mut reader)?;
+    println!("Archive:        {}", archive_summary(&metadata, layers, checksums));
 
-        sd += diff * diff;
+    let original = match original {
+        Some(path) => hgi::open(path)?.to_luma(),
+        None => return Ok(()),
+    };
+    if original.dimensions() != decoded.dimensions() {
+        return Err("original dimensions differ from the archive".into());
     }
 
-    let metadata = Metadata {
-        quantization_level: opts.quantization_level,
-        interpolation: InterpolationType::Crossed,
-        width: image_before.width(),
-        height: image_before.height(),
-        scale_level: opts.level,
+    // pixels of an error map keep their own bound until a layer refines the whole image
+    let bound = |x: u32, y: u32| match metadata.error_map {
+        Some(ref map) if layers == 0 => map.error_at((x, y)),
+        _ => max_error,
     };
-    let archive = Archive { metadata, grid };
-    let mut buffer = Vec::new();
-    archive.serialize_to_writer(&mut buffer)?;
+    let (worst, exceeding) = check_bound(&original, &decoded, bound);
+
+    println!("Maximum error:  {}", max_error);
+    if let Some(ref pixel) = worst {
+        println!(
+            "Worst pixel:    ({}, {}) original {} decoded {}, error {}",
+            pixel.x, pixel.y, pixel.original, pixel.decoded, pixel.error
+        );
+    }
+    match worst {
+        Some(pixel) if exceeding > 0 => {
+            Err(format!("{} pixels exceed their maximum error, the worst at ({}, {})", exceeding, pixel.x, pixel.y).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn archive_summary(metadata: &Metadata, layers: usize, checksums: bool) -> String {
+    let checksums = if checksums { "checksums match" } else { "no checksums (v0)" };
+    format!("{} tiles, {} layers decoded, {}", metadata.tile_count(), layers, checksums)
+}
+
+#[derive(Debug, PartialEq)]
+struct WorstPixel {
+    x: u32,
+    y: u32,
+    original: u8,
+    decoded: u8,
+    error: u8,
+}
+
+// The first pixel with the largest error along with the number of pixels exceeding their bound
+fn check_bound<F>(original: &GrayImage, decoded: &GrayImage, bound: F) -> (Option<WorstPixel>, usize)
+where
+    F: Fn(u32, u32) -> u8,
+{
+    let mut worst: Option<WorstPixel> = None;
+    let mut exceeding = 0usize;
+    for (x, y, pixel) in original.enumerate_pixels() {
+        let (before, after) = (pixel.data[0], decoded[(x, y)].data[0]);
+        let error = (i16::from(before) - i16::from(after)).unsigned_abs() as u8;
+        if error > bound(x, y) {
+            exceeding += 1;
+        }
+        if worst.as_ref().is_none_or(|worst| error > worst.error) {
+            worst = Some(WorstPixel { x, y, original: before, decoded: after, error });
+        }
+    }
+    (worst, exceeding)
+}
+
+fn print_metrics(metrics: &Metrics) {
+    println!("MSE:          {:.4}", metrics.mse);
+    println!("PSNR:         {:.2} dB", metrics.psnr);
+    println!("Max error:    {}", metrics.max_error);
+    println!("SSIM:         {:.6}", metrics.ssim);
+    println!("MS-SSIM:      {:.6}", metrics.ms_ssim);
+}
+
+fn compare(reference: &Path, image: &Path) -> Result<(), Box<dyn Error>> {
+    let reference = hgi::open(reference)?.to_luma();
+    let image = hgi::open(image)?.to_luma();
+    print_metrics(&Metrics::compute(&reference, &image)?);
+    Ok(())
+}
+
+fn bench_corpus(dir: &Path, recursive: bool, matrix: &bench::Matrix, output: Option<&Path>, json: bool) -> Result<(), Box<dyn Error>> {
+    let output = output.unwrap_or_else(|| Path::new(options::STDIO));
+    bench::run(dir, recursive, matrix, json, create_output(output)?)
+}
+
+fn rate_distortion(input: &Path, level: usize, errors: &[u8], qualities: &[u8], output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let image = open_image(input)?;
+    let output = output.unwrap_or_else(|| Path::new(options::STDIO));
+    bench::rate_distortion(&image, level, errors, qualities, create_output(output)?)
+}
+
+fn test(input: &Path, suffix: &str, opts: &EncodingOptions) -> Result<(), Box<dyn Error>> {
+    let image_before = hgi::open(input)?.to_luma();
+    let buffer = compress(&image_before, opts, Vec::new())?;
+    let (image_after, _) = decompress(&buffer[..])?;
+
+    let metrics = Metrics::compute(&image_before, &image_after)?;
 
     let uncompressed = image_before.height() * image_before.width();
-    sd /= uncompressed as usize;
     let compressed = buffer.len();
-    println!("Uncompressed: {} kb", uncompressed / 1024);
-    println!("Compressed:   {} kb", compressed / 1024);
+    println!("Uncompressed: {:.1} kb", f64::from(uncompressed) / 1024.0);
+    println!("Compressed:   {:.1} kb", compressed as f64 / 1024.0);
+    println!("Bits/pixel:   {:.3}", compressed as f64 * 8.0 / f64::from(uncompressed));
     println!("Ratio:        {:.2}", f64::from(uncompressed) / compressed as f64);
-    println!("SD:           {:.2}", (sd as f64).sqrt());
+    print_metrics(&metrics);
 
     let filename = input.file_stem().unwrap().to_string_lossy().into_owned() + suffix;
     image_after.save(filename.clone() + ".png")?;
@@ -119,10 +397,21 @@ fn test(input: &Path, suffix: &str, opts: &EncodingOptions) -> Result<(), Box<Er
     Ok(())
 }
 
-fn run() -> Result<(), Box<Error>> {
+fn run() -> Result<(), Box<dyn Error>> {
     match Opts::from_args() {
         Opts::Encode { io, options } => encode(&io, &options),
-        Opts::Decode { io } => decode(&io),
+        Opts::Decode { io, format } => decode(&io, format),
+        Opts::Thumbnail { io, max_size, format } => thumbnail(&io, max_size, format),
+        Opts::Info { input, json } => info(&input, json),
+        Opts::Verify { archive, original } => verify(&archive, original.as_deref()),
+        Opts::Compare { reference, image } => compare(&reference, &image),
+        Opts::BenchCorpus { dir, recursive, errors, levels, interpolations, codings, output, json } => {
+            let matrix = bench::Matrix { errors, levels, interpolations, codings };
+            bench_corpus(&dir, recursive, &matrix, output.as_deref(), json)
+        }
+        Opts::RateDistortion { input, level, errors, jpeg_qualities, output } => {
+            rate_distortion(&input, level, &errors, &jpeg_qualities, output.as_deref())
+        }
         Opts::Test { input, suffix, options } => test(&input, &suffix, &options)
     }
 }
@@ -130,5 +419,97 @@ fn run() -> Result<(), Box<Error>> {
 fn main() {
     if let Err(e) = run() {
         eprintln!("An error occured: {}", e);
+        process::exit(1);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use hgi::interpolator::InterpolationType;
+    use hgi::EncoderConfig;
+    use image::{DynamicImage, GrayImage, Luma};
+
+    use options::{Format, IO};
+    use super::{archive_summary, check_bound, decode, thumbnail, verify, WorstPixel};
+
+    #[test]
+    fn decode_previous_interpolation() {
+        let mut image = GrayImage::new(24, 16);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Luma([(x * 7 + y * y) as u8]);
+        }
+        let config = EncoderConfig::default().max_error(0).interpolation(InterpolationType::Previous);
+        let buffer = config.encode_to_writer(&DynamicImage::ImageLuma8(image.clone()), Vec::new()).unwrap();
+
+        let directory = env::temp_dir();
+        let archive = directory.join("hgi_cli_previous.hgi");
+        let decoded = directory.join("hgi_cli_previous.pgm");
+        let preview = directory.join("hgi_cli_previous_thumbnail.pgm");
+        fs::write(&archive, &buffer).unwrap();
+
+        let io = IO { input: vec![archive.clone()], output: decoded.clone(), recursive: false };
+        decode(&io, Some(Format::Pgm)).unwrap();
+        assert_eq!(hgi::open(&decoded).unwrap().to_luma().into_raw(), image.clone().into_raw());
+
+        let io = IO { input: vec![archive.clone()], output: preview.clone(), recursive: false };
+        thumbnail(&io, 64, Some(Format::Pgm)).unwrap();
+        assert_eq!(hgi::open(&preview).unwrap().to_luma().into_raw(), image.into_raw());
+
+        for path in &[archive, decoded, preview] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn verify_bound() {
+        let mut image = GrayImage::new(24, 16);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Luma([(x * 11 + y * 5) as u8]);
+        }
+        let config = EncoderConfig::default().max_error(10);
+        let buffer = config.encode_to_writer(&DynamicImage::ImageLuma8(image.clone()), Vec::new()).unwrap();
+        let decoded = hgi::decode_from_reader(&buffer[..]).unwrap().to_luma();
+
+        let directory = env::temp_dir();
+        let archive = directory.join("hgi_cli_verify.hgi");
+        let original = directory.join("hgi_cli_verify.png");
+        fs::write(&archive, &buffer).unwrap();
+        image.save(&original).unwrap();
+        verify(&archive, Some(&original)).unwrap();
+
+        // a single pixel far from its decoded value
+        let value = decoded[(5, 3)].data[0].wrapping_add(128);
+        image.put_pixel(5, 3, Luma([value]));
+        let (worst, exceeding) = check_bound(&image, &decoded, |_, _| 10);
+        let expected = WorstPixel { x: 5, y: 3, original: value, decoded: decoded[(5, 3)].data[0], error: 128 };
+        assert_eq!((worst, exceeding), (Some(expected), 1));
+        image.save(&original).unwrap();
+        let error = verify(&archive, Some(&original)).unwrap_err();
+        assert_eq!(error.to_string(), "1 pixels exceed their maximum error, the worst at (5, 3)");
+
+        // data of the last level right before the end chunk and the checksum
+        let mut corrupted = buffer.clone();
+        let index = corrupted.len() - 17;
+        corrupted[index] ^= 1;
+        fs::write(&archive, &corrupted).unwrap();
+        match verify(&archive, None).unwrap_err().downcast_ref::<hgi::Error>() {
+            Some(&hgi::Error::Corrupted(_)) => {}
+            error => panic!("corrupted archive verified: {:?}", error),
+        }
+
+        for path in &[archive, original] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn verify_summary() {
+        let image = DynamicImage::ImageLuma8(GrayImage::new(24, 16));
+        let buffer = EncoderConfig::default().encode_to_writer(&image, Vec::new()).unwrap();
+        let metadata = hgi::ArchiveReader::new(&buffer[..]).unwrap().metadata().clone();
+        assert_eq!(archive_summary(&metadata, 0, true), "1 tiles, 0 layers decoded, checksums match");
+        assert_eq!(archive_summary(&metadata, 2, false), "1 tiles, 2 layers decoded, no checksums (v0)");
+    }
+}
@@ -0,0 +1,206 @@
+use image::GrayImage;
+
+use error::{invalid_dimensions, Error};
+
+const PEAK: f64 = 255.0;
+// Stabilizing constants of SSIM, (0.01 * L)^2 and (0.03 * L)^2
+const C1: f64 = (0.01 * PEAK) * (0.01 * PEAK);
+const C2: f64 = (0.03 * PEAK) * (0.03 * PEAK);
+const WINDOW_SIZE: usize = 11;
+const WINDOW_SIGMA: f64 = 1.5;
+// Weights of the scales of MS-SSIM, from the finest
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// Quality of an image compared with its reference.
+#[derive(Clone, Copy, Serialize, Debug, PartialEq)]
+pub struct Metrics {
+    pub mse: f64,
+    /// Infinite for identical images
+    pub psnr: f64,
+    pub max_error: u8,
+    pub ssim: f64,
+    pub ms_ssim: f64,
+}
+
+impl Metrics {
+    pub fn compute(reference: &GrayImage, image: &GrayImage) -> Result<Self, Error> {
+        let mse = mse(reference, image)?;
+        Ok(Metrics {
+            mse,
+            psnr: psnr_of(mse),
+            max_error: max_error(reference, image)?,
+            ssim: ssim(reference, image)?,
+            ms_ssim: ms_ssim(reference, image)?,
+        })
+    }
+}
+
+fn check_dimensions(a: &GrayImage, b: &GrayImage) -> Result<(), Error> {
+    if a.dimensions() != b.dimensions() {
+        return Err(invalid_dimensions(format!(
+            "compared images are {}x{} and {}x{}",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        )));
+    }
+    if a.width() == 0 || a.height() == 0 {
+        return Err(invalid_dimensions("compared images are empty"));
+    }
+    Ok(())
+}
+
+/// Mean squared error of the pixels.
+pub fn mse(a: &GrayImage, b: &GrayImage) -> Result<f64, Error> {
+    check_dimensions(a, b)?;
+    let sum: u64 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(&a, &b)| {
+            let diff = u64::from((i16::from(a) - i16::from(b)).unsigned_abs());
+            diff * diff
+        })
+        .sum();
+    Ok(sum as f64 / a.len() as f64)
+}
+
+/// Peak signal-to-noise ratio in decibels.
+pub fn psnr(a: &GrayImage, b: &GrayImage) -> Result<f64, Error> {
+    Ok(psnr_of(mse(a, b)?))
+}
+
+fn psnr_of(mse: f64) -> f64 {
+    10.0 * (PEAK * PEAK / mse).log10()
+}
+
+/// Largest absolute difference of two pixels.
+pub fn max_error(a: &GrayImage, b: &GrayImage) -> Result<u8, Error> {
+    check_dimensions(a, b)?;
+    Ok(a.iter().zip(b.iter()).map(|(&a, &b)| a.max(b) - a.min(b)).max().unwrap_or(0))
+}
+
+/// Structural similarity with an 11x11 gaussian window of deviation 1.5, averaged over the
+/// window positions inside the image. Smaller images use a window as large as their smaller side.
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> Result<f64, Error> {
+    check_dimensions(a, b)?;
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let (ssim, _) = similarity(&to_plane(a), &to_plane(b), width, height);
+    Ok(ssim)
+}
+
+/// Multi-scale structural similarity over five scales halving the image, fewer when the image
+/// gets smaller than the window, with the weights renormalized.
+pub fn ms_ssim(a: &GrayImage, b: &GrayImage) -> Result<f64, Error> {
+    check_dimensions(a, b)?;
+    let (mut width, mut height) = (a.width() as usize, a.height() as usize);
+    let (mut a, mut b) = (to_plane(a), to_plane(b));
+
+    let mut scales = 1;
+    while scales < MS_SSIM_WEIGHTS.len() && (width >> scales).min(height >> scales) >= WINDOW_SIZE {
+        scales += 1;
+    }
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let total: f64 = weights.iter().sum();
+
+    let mut result = 1.0;
+    for (scale, weight) in weights.iter().enumerate() {
+        let (ssim, cs) = similarity(&a, &b, width, height);
+        // the luminance term only counts at the coarsest scale
+        let value = if scale + 1 == scales { ssim } else { cs };
+        result *= value.max(0.0).powf(weight / total);
+
+        if scale + 1 < scales {
+            a = downsample(&a, width, height);
+            b = downsample(&b, width, height);
+            width /= 2;
+            height /= 2;
+        }
+    }
+    Ok(result)
+}
+
+fn to_plane(image: &GrayImage) -> Vec<f64> {
+    image.iter().map(|&pixel| f64::from(pixel)).collect()
+}
+
+// Averages of 2x2 blocks, dropping an odd last line or column
+fn downsample(plane: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let (half_width, half_height) = (width / 2, height / 2);
+    let mut result = Vec::with_capacity(half_width * half_height);
+    for y in 0..half_height {
+        let (top, bottom) = (&plane[2 * y * width..][..width], &plane[(2 * y + 1) * width..][..width]);
+        for x in 0..half_width {
+            result.push((top[2 * x] + top[2 * x + 1] + bottom[2 * x] + bottom[2 * x + 1]) / 4.0);
+        }
+    }
+    result
+}
+
+fn gaussian_window(size: usize) -> Vec<f64> {
+    let center = (size / 2) as f64;
+    let window: Vec<f64> = (0..size)
+        .map(|i| {
+            let x = i as f64 - center;
+            (-x * x / (2.0 * WINDOW_SIGMA * WINDOW_SIGMA)).exp()
+        })
+        .collect();
+    let sum: f64 = window.iter().sum();
+    window.into_iter().map(|w| w / sum).collect()
+}
+
+// Separable filtering keeping only the positions where the window fits in the plane
+fn filter(plane: &[f64], width: usize, height: usize, window: &[f64]) -> Vec<f64> {
+    let size = window.len();
+    let (out_width, out_height) = (width - size + 1, height - size + 1);
+
+    let mut rows: Vec<f64> = Vec::with_capacity(out_width * height);
+    for line in plane.chunks(width) {
+        for x in 0..out_width {
+            rows.push(line[x..x + size].iter().zip(window).map(|(p, w)| p * w).sum());
+        }
+    }
+
+    let mut result: Vec<f64> = Vec::with_capacity(out_width * out_height);
+    for y in 0..out_height {
+        for x in 0..out_width {
+            result.push((0..size).map(|i| rows[(y + i) * out_width + x] * window[i]).sum());
+        }
+    }
+    result
+}
+
+// Mean SSIM and mean contrast-structure term of two planes
+fn similarity(a: &[f64], b: &[f64], width: usize, height: usize) -> (f64, f64) {
+    let mut size = WINDOW_SIZE.min(width).min(height);
+    // keep the window centered
+    if size.is_multiple_of(2) {
+        size -= 1;
+    }
+    let window = gaussian_window(size);
+    let products = |f: &dyn Fn(f64, f64) -> f64| -> Vec<f64> {
+        let plane: Vec<f64> = a.iter().zip(b).map(|(&a, &b)| f(a, b)).collect();
+        filter(&plane, width, height, &window)
+    };
+
+    let mean_a = filter(a, width, height, &window);
+    let mean_b = filter(b, width, height, &window);
+    let square_a = products(&|a, _| a * a);
+    let square_b = products(&|_, b| b * b);
+    let cross = products(&|a, b| a * b);
+
+    let (mut ssim, mut cs) = (0.0, 0.0);
+    for i in 0..mean_a.len() {
+        let (mean_a, mean_b) = (mean_a[i], mean_b[i]);
+        let variance_a = square_a[i] - mean_a * mean_a;
+        let variance_b = square_b[i] - mean_b * mean_b;
+        let covariance = cross[i] - mean_a * mean_b;
+
+        let luminance = (2.0 * mean_a * mean_b + C1) / (mean_a * mean_a + mean_b * mean_b + C1);
+        let contrast_structure = (2.0 * covariance + C2) / (variance_a + variance_b + C2);
+        ssim += luminance * contrast_structure;
+        cs += contrast_structure;
+    }
+    let count = mean_a.len() as f64;
+    (ssim / count, cs / count)
+}
@@ -1,6 +1,8 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use quantizator::QuantizationLevel;
+use hgi::interpolator::InterpolationType;
+use hgi::quantizator::QuantizationLevel;
+use hgi::Coding;
 
 
 #[derive(StructOpt, Debug)]
@@ -23,7 +25,124 @@ pub enum Opts {
     #[structopt(name = "decode")]
     Decode {
         #[structopt(flatten)]
-        io: IO
+        io: IO,
+
+        /// Format of the decoded image, by default from the output extension or PNG on stdout
+        #[structopt(long = "format", raw(possible_values = "&Format::variants()", case_insensitive = "true"))]
+        format: Option<Format>
+    },
+
+    /// Writes a preview decoded from the coarse levels only
+    #[structopt(name = "thumbnail")]
+    Thumbnail {
+        #[structopt(flatten)]
+        io: IO,
+
+        /// Maximum width and height of the preview
+        #[structopt(long = "max-size", default_value = "256")]
+        max_size: u32,
+
+        /// Format of the preview, by default from the output extension or PNG on stdout
+        #[structopt(long = "format", raw(possible_values = "&Format::variants()", case_insensitive = "true"))]
+        format: Option<Format>
+    },
+
+    /// Prints the metadata and sizes of an archive without decoding it
+    #[structopt(name = "info")]
+    Info {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Print as JSON
+        #[structopt(long = "json")]
+        json: bool
+    },
+
+    /// Decodes an archive checking its checksums and, given the original, its maximum error
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(long = "archive", parse(from_os_str))]
+        archive: PathBuf,
+
+        /// Source image every decoded pixel is compared with
+        #[structopt(long = "original", parse(from_os_str))]
+        original: Option<PathBuf>
+    },
+
+    /// Prints MSE, PSNR, maximum error, SSIM and MS-SSIM of an image against a reference
+    #[structopt(name = "compare")]
+    Compare {
+        #[structopt(parse(from_os_str))]
+        reference: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        image: PathBuf
+    },
+
+    /// Codes every image of a directory with every combination of settings, reporting sizes, quality and times
+    #[structopt(name = "bench-corpus")]
+    BenchCorpus {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+
+        /// Take the images of the subdirectories too
+        #[structopt(short = "r", long = "recursive")]
+        recursive: bool,
+
+        /// Maximum errors to sweep
+        #[structopt(long = "errors", raw(use_delimiter = "true"), default_value = "0,5,10,20")]
+        errors: Vec<u8>,
+
+        /// Numbers of levels to sweep
+        #[structopt(long = "levels", raw(use_delimiter = "true"), default_value = "4")]
+        levels: Vec<usize>,
+
+        /// Interpolations to sweep
+        #[structopt(
+            long = "interpolations",
+            raw(use_delimiter = "true", possible_values = "&InterpolationType::variants()", case_insensitive = "true"),
+            default_value = "Crossed,Previous"
+        )]
+        interpolations: Vec<InterpolationType>,
+
+        /// Codings to sweep: progressive, tiled:<size> or layered:<error>/<error>/...
+        #[structopt(
+            long = "codings",
+            raw(use_delimiter = "true"),
+            parse(try_from_str = "parse_coding"),
+            default_value = "progressive"
+        )]
+        codings: Vec<Coding>,
+
+        /// Report file, stdout by default
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Report as JSON instead of CSV
+        #[structopt(long = "json")]
+        json: bool
+    },
+
+    /// Writes bits per pixel, PSNR and maximum error of HGI, PNG and JPEG at several settings as CSV
+    #[structopt(name = "rate-distortion")]
+    RateDistortion {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        #[structopt(short = "l", long = "level", default_value = "4")]
+        level: usize,
+
+        /// Maximum errors of the HGI series
+        #[structopt(long = "errors", raw(use_delimiter = "true"), default_value = "0,1,2,3,5,8,10,15,20,30,40")]
+        errors: Vec<u8>,
+
+        /// Qualities of the JPEG series
+        #[structopt(long = "jpeg-qualities", raw(use_delimiter = "true"), default_value = "10,20,30,40,50,60,70,80,90,95,100")]
+        jpeg_qualities: Vec<u8>,
+
+        /// CSV file, stdout by default
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>
     },
 
     #[structopt(name = "test")]
@@ -39,13 +158,26 @@ pub enum Opts {
     },
 }
 
+/// Path standing for stdin as input and stdout as output.
+pub const STDIO: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
 #[derive(StructOpt, Debug)]
 pub struct IO {
-    #[structopt(short = "i", long = "input", parse(from_os_str))]
-    pub input: PathBuf,
+    /// Input files or directories, several inputs or a directory make the output a directory. - is stdin
+    #[structopt(short = "i", long = "input", parse(from_os_str), raw(required = "true"))]
+    pub input: Vec<PathBuf>,
 
+    /// Output file or directory, - is stdout
     #[structopt(short = "o", long = "output", parse(from_os_str))]
     pub output: PathBuf,
+
+    /// Take the files of the subdirectories of input directories too
+    #[structopt(short = "r", long = "recursive")]
+    pub recursive: bool,
 }
 
 
@@ -62,4 +194,115 @@ pub struct EncodingOptions {
         default_value = "medium"
     )]
     pub quantization_level: QuantizationLevel,
-}
\ No newline at end of file
+
+    /// Region of interest mask, blocks with non-zero pixels use the ROI quantizator
+    #[structopt(long = "roi", parse(from_os_str))]
+    pub roi: Option<PathBuf>,
+
+    #[structopt(
+        long = "roi-quantizator",
+        raw(possible_values = "&QuantizationLevel::variants()", case_insensitive = "true"),
+        default_value = "lossless"
+    )]
+    pub roi_quantization_level: QuantizationLevel,
+
+    /// Grayscale image with the maximum error of every pixel, overrides quantizator and ROI
+    #[structopt(long = "error-map", parse(from_os_str))]
+    pub error_map: Option<PathBuf>,
+
+    /// Split the image into independently coded square tiles of this size
+    #[structopt(short = "t", long = "tile-size")]
+    pub tile_size: Option<u32>,
+
+    /// Maximum errors of the quality layers refining the image, in decreasing order
+    #[structopt(long = "layers", raw(use_delimiter = "true"))]
+    pub layers: Vec<u8>,
+}
+
+arg_enum! {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Pgm,
+    Tiff,
+}
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Pgm => "pgm",
+            Format::Tiff => "tiff",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match &extension[..] {
+            "png" => Some(Format::Png),
+            "pgm" => Some(Format::Pgm),
+            "tif" | "tiff" => Some(Format::Tiff),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `progressive`, `tiled:<size>` or `layered:<error>/<error>/...`.
+pub fn parse_coding(coding: &str) -> Result<Coding, String> {
+    let mut parts = coding.splitn(2, ':');
+    let name = parts.next().unwrap_or("").to_ascii_lowercase();
+    match (&name[..], parts.next()) {
+        ("progressive", None) => Ok(Coding::Progressive),
+        ("tiled", Some(size)) => size.parse().map(Coding::Tiled).map_err(|e| format!("tile size {}: {}", size, e)),
+        ("layered", Some(errors)) => errors
+            .split('/')
+            .map(|error| error.parse().map_err(|e| format!("layer error {}: {}", error, e)))
+            .collect::<Result<_, _>>()
+            .map(Coding::Layered),
+        _ => Err(format!("{} is not progressive, tiled:<size> or layered:<error>/<error>/...", coding)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use hgi::Coding;
+
+    use super::{is_stdio, parse_coding, Format};
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Format::from_path(Path::new("out.png")), Some(Format::Png));
+        assert_eq!(Format::from_path(Path::new("dir/out.PGM")), Some(Format::Pgm));
+        assert_eq!(Format::from_path(Path::new("out.tif")), Some(Format::Tiff));
+        assert_eq!(Format::from_path(Path::new("out.Tiff")), Some(Format::Tiff));
+        assert_eq!(Format::from_path(Path::new("out.jpg")), None);
+        assert_eq!(Format::from_path(Path::new("out")), None);
+        assert_eq!(Format::from_path(Path::new("-")), None);
+    }
+
+    #[test]
+    fn stdio_path() {
+        assert!(is_stdio(Path::new("-")));
+        assert!(!is_stdio(Path::new("./-")));
+        assert!(!is_stdio(Path::new("-.png")));
+    }
+
+    #[test]
+    fn coding() {
+        assert_eq!(parse_coding("progressive"), Ok(Coding::Progressive));
+        assert_eq!(parse_coding("Progressive"), Ok(Coding::Progressive));
+        assert_eq!(parse_coding("tiled:256"), Ok(Coding::Tiled(256)));
+        assert_eq!(parse_coding("layered:10/2/0"), Ok(Coding::Layered(vec![10, 2, 0])));
+        assert_eq!(parse_coding("layered:5"), Ok(Coding::Layered(vec![5])));
+
+        let invalid = [
+            "", "mosaic", "progressive:1", "tiled", "tiled:", "tiled:-1", "tiled:x", "layered", "layered:10//0", "layered:300",
+        ];
+        for coding in &invalid {
+            assert!(parse_coding(coding).is_err(), "{} was accepted", coding);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use error::{invalid, invalid_dimensions, Error};
+use error_map::ErrorMap;
+use interpolator::Segment;
+
 arg_enum! {
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum QuantizationLevel {
@@ -8,10 +12,55 @@ pub enum QuantizationLevel {
 }
 }
 
+impl QuantizationLevel {
+    pub fn error(self) -> u8 {
+        match self {
+            QuantizationLevel::Lossless => 0,
+            QuantizationLevel::Low => 10,
+            QuantizationLevel::Medium => 20,
+            QuantizationLevel::High => 30,
+        }
+    }
+
+    /// The level with exactly this error, if any.
+    pub fn from_error(error: u8) -> Option<Self> {
+        let levels = [
+            QuantizationLevel::Lossless,
+            QuantizationLevel::Low,
+            QuantizationLevel::Medium,
+            QuantizationLevel::High,
+        ];
+        levels.iter().cloned().find(|level| level.error() == error)
+    }
+}
 
-pub trait Quantizator : From<QuantizationLevel> {
-    fn quantize(&self, value: u8) -> u8;
+pub trait Quantizator: Sync {
+    /// Returns the residue to store for `actual` at `at` predicted as `prediction`.
+    /// `prediction.wrapping_add(residue)` never differs from `actual` by more than `error()`.
+    fn quantize(&self, at: (u32, u32), prediction: u8, actual: u8) -> u8;
     fn error(&self) -> u8;
+
+    /// Checks that `quantize` accepts every pixel of the `width x height` area at `origin`.
+    fn check_area(&self, _origin: (u32, u32), _dimensions: (u32, u32)) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Quantizes every node of the segment exactly as `quantize` does,
+    /// `origin` is added to the node positions.
+    fn quantize_segment(&self, origin: (u32, u32), segment: Segment, predictions: &[u8], actual: &[u8], residues: &mut [u8]) {
+        let (x, y) = origin;
+        let nodes = predictions.iter().zip(actual).zip(segment.columns());
+        for (residue, ((&prediction, &actual), column)) in residues.iter_mut().zip(nodes) {
+            *residue = self.quantize((x + column, y + segment.line), prediction, actual);
+        }
+    }
+}
+
+#[inline(always)]
+fn wrapping_residues(predictions: &[u8], actual: &[u8], residues: &mut [u8]) {
+    for (residue, (&prediction, &actual)) in residues.iter_mut().zip(predictions.iter().zip(actual)) {
+        *residue = actual.wrapping_sub(prediction);
+    }
 }
 
 pub struct NoOp;
@@ -24,51 +73,124 @@ impl From<QuantizationLevel> for NoOp {
 
 impl Quantizator for NoOp {
     #[inline(always)]
-    fn quantize(&self, value: u8) -> u8 {
-        value
+    fn quantize(&self, _at: (u32, u32), prediction: u8, actual: u8) -> u8 {
+        actual.wrapping_sub(prediction)
     }
 
     fn error(&self) -> u8 {
         0
     }
+
+    fn quantize_segment(&self, _origin: (u32, u32), _segment: Segment, predictions: &[u8], actual: &[u8], residues: &mut [u8]) {
+        wrapping_residues(predictions, actual, residues);
+    }
 }
 
+pub const MAX_ERROR: u8 = 127;
+
 pub struct Linear {
-    table: [u8; 256],
+    // quantized difference for every `actual - prediction` in -255..=255
+    table: [i16; 511],
     error: u8
 }
 
-impl From<QuantizationLevel> for Linear {
-    fn from(level: QuantizationLevel) -> Self {
-        let error: u8 = match level {
-            QuantizationLevel::Lossless => 0,
-            QuantizationLevel::Low => 10,
-            QuantizationLevel::Medium => 20,
-            QuantizationLevel::High => 30,
-        };
+impl Linear {
+    /// Fails for errors above `MAX_ERROR`.
+    pub fn new(error: u8) -> Result<Self, Error> {
+        if error > MAX_ERROR {
+            return Err(invalid(format!("quantization error must not exceed {}", MAX_ERROR)));
+        }
+        Ok(Linear::with_error(error))
+    }
 
-        let scale = 2 * error as usize + 1;
-        let quantize = |x| {
-            let r = (x as usize + error as usize) / scale;
-            let v = r * scale;
-            v as u8
+    // The error is known not to exceed MAX_ERROR
+    fn with_error(error: u8) -> Self {
+        let scale = 2 * i16::from(error) + 1;
+        let quantize = |diff: i16| {
+            let r = (diff.abs() + i16::from(error)) / scale;
+            diff.signum() * r * scale
         };
 
-        let mut table = [0; 256];
+        let mut table = [0; 511];
         for (i, entry) in table.iter_mut().enumerate() {
-            *entry = quantize(i as u8);
+            *entry = quantize(i as i16 - 255);
         }
         Linear { table, error }
     }
 }
 
+impl From<QuantizationLevel> for Linear {
+    fn from(level: QuantizationLevel) -> Self {
+        Linear::with_error(level.error())
+    }
+}
+
+impl Linear {
+    #[inline(always)]
+    fn quantize_value(&self, prediction: u8, actual: u8) -> u8 {
+        let diff = i16::from(actual) - i16::from(prediction);
+        let quanted_diff = self.table[(diff + 255) as usize];
+        // clamping keeps the error bound: `actual` itself lies in 0..=255
+        let value = (i16::from(prediction) + quanted_diff).clamp(0, 255) as u8;
+        value.wrapping_sub(prediction)
+    }
+}
+
 impl Quantizator for Linear {
     #[inline(always)]
-    fn quantize(&self, value: u8) -> u8 {
-        self.table[value as usize]
+    fn quantize(&self, _at: (u32, u32), prediction: u8, actual: u8) -> u8 {
+        self.quantize_value(prediction, actual)
     }
 
     fn error(&self) -> u8 {
         self.error
     }
-}
\ No newline at end of file
+
+    // lossless residues are plain differences
+    fn quantize_segment(&self, _origin: (u32, u32), _segment: Segment, predictions: &[u8], actual: &[u8], residues: &mut [u8]) {
+        if self.error == 0 {
+            return wrapping_residues(predictions, actual, residues);
+        }
+        for (residue, (&prediction, &actual)) in residues.iter_mut().zip(predictions.iter().zip(actual)) {
+            *residue = self.quantize_value(prediction, actual);
+        }
+    }
+}
+
+/// Linear quantization with the error chosen per pixel by an `ErrorMap`.
+pub struct Regional {
+    map: ErrorMap,
+    // indexed by error
+    quantizators: Vec<Linear>,
+}
+
+impl Regional {
+    pub fn new(map: ErrorMap) -> Self {
+        let quantizators = (0..=map.max_error()).map(Linear::with_error).collect();
+        Regional { map, quantizators }
+    }
+
+    pub fn map(&self) -> &ErrorMap {
+        &self.map
+    }
+}
+
+impl Quantizator for Regional {
+    #[inline(always)]
+    fn quantize(&self, at: (u32, u32), prediction: u8, actual: u8) -> u8 {
+        let error = self.map.error_at(at);
+        self.quantizators[error as usize].quantize_value(prediction, actual)
+    }
+
+    fn error(&self) -> u8 {
+        self.map.max_error()
+    }
+
+    fn check_area(&self, (x, y): (u32, u32), (width, height): (u32, u32)) -> Result<(), Error> {
+        let (map_width, map_height) = self.map.dimensions();
+        if u64::from(x) + u64::from(width) > u64::from(map_width) || u64::from(y) + u64::from(height) > u64::from(map_height) {
+            return Err(invalid_dimensions("error map is smaller than the image"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+// Crossed predictions of a row of cells: `upper` and `lower` hold the corner values of
+// the cells along two coarse lines, `predictions[k]` is the prediction for the cell
+// with corners `k` and `k + 1`, so `upper` and `lower` are one longer than `predictions`.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::arch::is_x86_feature_detected;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+#[inline(always)]
+pub fn predict(left_top: u8, left_bot: u8, right_top: u8, right_bot: u8) -> u8 {
+    let average = |x, y| (x as usize + y as usize + 1) >> 1; // div 2
+
+    let left  = average(left_top,  left_bot);
+    let right = average(right_bot, right_top);
+    let top   = average(right_top, left_top);
+    let bot   = average(right_bot, left_bot);
+
+    ((left + right + top + bot) >> 2) as u8 // div 4
+}
+
+pub fn predict_cells_scalar(upper: &[u8], lower: &[u8], predictions: &mut [u8]) {
+    for (k, prediction) in predictions.iter_mut().enumerate() {
+        *prediction = predict(upper[k], upper[k + 1], lower[k], lower[k + 1]);
+    }
+}
+
+/// Picks the widest instruction set the CPU supports.
+pub fn predict_cells(upper: &[u8], lower: &[u8], predictions: &mut [u8]) {
+    assert!(upper.len() > predictions.len() && lower.len() > predictions.len());
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { predict_cells_avx2(upper, lower, predictions) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { predict_cells_sse2(upper, lower, predictions) };
+        }
+    }
+    predict_cells_scalar(upper, lower, predictions)
+}
+
+// `_mm_avg_epu8` rounds up like `average`, the sum of four averages needs 16 bits
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+pub unsafe fn predict_cells_sse2(upper: &[u8], lower: &[u8], predictions: &mut [u8]) {
+    assert!(upper.len() > predictions.len() && lower.len() > predictions.len());
+
+    let count = predictions.len() - predictions.len() % 16;
+    let zero = _mm_setzero_si128();
+    for k in (0..count).step_by(16) {
+        let load = |values: &[u8], at: usize| _mm_loadu_si128(values.as_ptr().add(at) as *const __m128i);
+        let left_top = load(upper, k);
+        let left_bot = load(upper, k + 1);
+        let right_top = load(lower, k);
+        let right_bot = load(lower, k + 1);
+
+        let left = _mm_avg_epu8(left_top, left_bot);
+        let right = _mm_avg_epu8(right_bot, right_top);
+        let top = _mm_avg_epu8(right_top, left_top);
+        let bot = _mm_avg_epu8(right_bot, left_bot);
+
+        let sum = |unpack: unsafe fn(__m128i, __m128i) -> __m128i| {
+            let sum = _mm_add_epi16(unpack(left, zero), unpack(right, zero));
+            let sum = _mm_add_epi16(sum, unpack(top, zero));
+            _mm_srli_epi16(_mm_add_epi16(sum, unpack(bot, zero)), 2)
+        };
+        let low = sum(_mm_unpacklo_epi8);
+        let high = sum(_mm_unpackhi_epi8);
+        _mm_storeu_si128(predictions.as_mut_ptr().add(k) as *mut __m128i, _mm_packus_epi16(low, high));
+    }
+
+    predict_cells_scalar(&upper[count..], &lower[count..], &mut predictions[count..]);
+}
+
+// Unpacking and packing work within 128 bit lanes, so the order of the bytes is kept
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+pub unsafe fn predict_cells_avx2(upper: &[u8], lower: &[u8], predictions: &mut [u8]) {
+    assert!(upper.len() > predictions.len() && lower.len() > predictions.len());
+
+    let count = predictions.len() - predictions.len() % 32;
+    let zero = _mm256_setzero_si256();
+    for k in (0..count).step_by(32) {
+        let load = |values: &[u8], at: usize| _mm256_loadu_si256(values.as_ptr().add(at) as *const __m256i);
+        let left_top = load(upper, k);
+        let left_bot = load(upper, k + 1);
+        let right_top = load(lower, k);
+        let right_bot = load(lower, k + 1);
+
+        let left = _mm256_avg_epu8(left_top, left_bot);
+        let right = _mm256_avg_epu8(right_bot, right_top);
+        let top = _mm256_avg_epu8(right_top, left_top);
+        let bot = _mm256_avg_epu8(right_bot, left_bot);
+
+        let sum = |unpack: unsafe fn(__m256i, __m256i) -> __m256i| {
+            let sum = _mm256_add_epi16(unpack(left, zero), unpack(right, zero));
+            let sum = _mm256_add_epi16(sum, unpack(top, zero));
+            _mm256_srli_epi16(_mm256_add_epi16(sum, unpack(bot, zero)), 2)
+        };
+        let low = sum(_mm256_unpacklo_epi8);
+        let high = sum(_mm256_unpackhi_epi8);
+        _mm256_storeu_si256(predictions.as_mut_ptr().add(k) as *mut __m256i, _mm256_packus_epi16(low, high));
+    }
+
+    predict_cells_sse2(&upper[count..], &lower[count..], &mut predictions[count..]);
+}
@@ -0,0 +1,80 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use byteorder::{WriteBytesExt, LE};
+use image::GrayImage;
+
+// Field types of the directory entries
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+const ENTRIES: u16 = 9;
+// Header, entry count, entries and next directory offset precede the pixels
+const DATA_OFFSET: u32 = 8 + 2 + ENTRIES as u32 * 12 + 4;
+
+/// Writes an uncompressed baseline TIFF of a grayscale image in a single strip,
+/// the `image` crate only decodes them.
+pub fn write_gray<W: Write>(w: &mut W, image: &GrayImage) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let size = u32::try_from(image.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "image is too large for TIFF"))?;
+
+    w.write_all(b"II")?;
+    w.write_u16::<LE>(42)?;
+    w.write_u32::<LE>(8)?;
+
+    w.write_u16::<LE>(ENTRIES)?;
+    entry(w, 256, LONG, width)?; // ImageWidth
+    entry(w, 257, LONG, height)?; // ImageLength
+    entry(w, 258, SHORT, 8)?; // BitsPerSample
+    entry(w, 259, SHORT, 1)?; // Compression: none
+    entry(w, 262, SHORT, 1)?; // PhotometricInterpretation: black is zero
+    entry(w, 273, LONG, DATA_OFFSET)?; // StripOffsets
+    entry(w, 277, SHORT, 1)?; // SamplesPerPixel
+    entry(w, 278, LONG, height)?; // RowsPerStrip
+    entry(w, 279, LONG, size)?; // StripByteCounts
+    w.write_u32::<LE>(0)?;
+
+    w.write_all(image)
+}
+
+// Entry with a single value, short values are left-justified in the value field
+fn entry<W: Write>(w: &mut W, tag: u16, field_type: u16, value: u32) -> io::Result<()> {
+    w.write_u16::<LE>(tag)?;
+    w.write_u16::<LE>(field_type)?;
+    w.write_u32::<LE>(1)?;
+    if field_type == SHORT {
+        w.write_u16::<LE>(value as u16)?;
+        w.write_u16::<LE>(0)
+    } else {
+        w.write_u32::<LE>(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hgi;
+    use image::{self, GrayImage, ImageFormat, Luma};
+
+    use super::write_gray;
+
+    #[test]
+    fn roundtrip() {
+        for &(width, height) in &[(1, 1), (7, 5), (64, 3)] {
+            let mut image = GrayImage::new(width, height);
+            for (x, y, pixel) in image.enumerate_pixels_mut() {
+                *pixel = Luma([(x * 31 + y * 17) as u8]);
+            }
+            let mut buffer = Vec::new();
+            write_gray(&mut buffer, &image).unwrap();
+            assert_eq!(buffer.len(), 122 + image.len());
+
+            let decoded = image::load_from_memory_with_format(&buffer, ImageFormat::TIFF).unwrap().to_luma();
+            assert_eq!(decoded.dimensions(), (width, height));
+            assert_eq!(decoded.into_raw(), image.clone().into_raw());
+            // stdin input is recognized without an extension
+            let loaded = hgi::load_from_memory(&buffer).unwrap().to_luma();
+            assert_eq!(loaded.into_raw(), image.into_raw());
+        }
+    }
+}
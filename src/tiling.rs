@@ -0,0 +1,77 @@
+use error::{invalid, Error};
+
+/// Rectangle of image pixels
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect { x, y, width, height }
+    }
+
+    /// Common part of the rectangles, ends past `u32::MAX` are clamped to it.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let (x1, y1) = (self.x.max(other.x), self.y.max(other.y));
+        let x2 = self.x.saturating_add(self.width).min(other.x.saturating_add(other.width));
+        let y2 = self.y.saturating_add(self.height).min(other.y.saturating_add(other.height));
+        if x1 < x2 && y1 < y2 {
+            Some(Rect::new(x1, y1, x2 - x1, y2 - y1))
+        } else {
+            None
+        }
+    }
+}
+
+/// Split of an image into independently coded tiles, in line order.
+/// Tiles on the right and bottom edges are cropped to the image.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Tiling {
+    pub tile_width: u32,
+    pub tile_height: u32,
+}
+
+impl Tiling {
+    /// Fails for a zero tile size.
+    pub fn new(tile_width: u32, tile_height: u32) -> Result<Self, Error> {
+        let tiling = Tiling { tile_width, tile_height };
+        tiling.check()?;
+        Ok(tiling)
+    }
+
+    // The fields are public, so tilings built without `new` are checked before use
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.tile_width == 0 || self.tile_height == 0 {
+            return Err(invalid("tile size must not be zero"));
+        }
+        Ok(())
+    }
+
+    pub fn columns(&self, width: u32) -> u32 {
+        width.div_ceil(self.tile_width)
+    }
+
+    pub fn lines(&self, height: u32) -> u32 {
+        height.div_ceil(self.tile_height)
+    }
+
+    pub fn count(&self, (width, height): (u32, u32)) -> usize {
+        self.columns(width) as usize * self.lines(height) as usize
+    }
+
+    pub fn tile(&self, index: usize, (width, height): (u32, u32)) -> Rect {
+        let columns = self.columns(width) as usize;
+        let x = (index % columns) as u32 * self.tile_width;
+        let y = (index / columns) as u32 * self.tile_height;
+        Rect::new(x, y, self.tile_width.min(width - x), self.tile_height.min(height - y))
+    }
+
+    pub fn tiles(&self, dimensions: (u32, u32)) -> impl Iterator<Item = Rect> {
+        let tiling = *self;
+        (0..self.count(dimensions)).map(move |index| tiling.tile(index, dimensions))
+    }
+}
@@ -1,10 +1,49 @@
-use image::Luma;
+use image::{GrayImage, Luma};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use interpolator::Segment;
+use tiling::Rect;
 
 #[inline(always)]
 pub fn gray(value: u8) -> Luma<u8> {
     Luma { data: [value] }
 }
 
+/// Number of tiles worth processing at once.
+#[cfg(feature = "parallel")]
+pub fn batch_size() -> usize {
+    ::rayon::current_num_threads()
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn batch_size() -> usize {
+    1
+}
+
+/// Maps every item keeping their order, on all threads with the `parallel` feature.
+#[cfg(feature = "parallel")]
+pub fn map_ordered<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+where
+    T: Send,
+    U: Send,
+    F: Fn(T) -> U + Sync + Send,
+{
+    items.into_par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn map_ordered<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+where
+    F: Fn(T) -> U,
+{
+    items.into_iter().map(f).collect()
+}
+
+pub fn crop(image: &GrayImage, rect: Rect) -> GrayImage {
+    GrayImage::from_fn(rect.width, rect.height, |x, y| *image.get_pixel(rect.x + x, rect.y + y))
+}
+
 // x is column
 // y is line
 
@@ -22,7 +61,7 @@ where
     while line < y2 {
         let mut column = start;
         while column < x2 {
-            f(column as u32, line as u32);
+            f(column, line);
             column += step;
         }
 
@@ -33,9 +72,113 @@ where
 
         let mut column = x1;
         while column < x2 {
-            f(column as u32, line as u32);
+            f(column, line);
             column += substep;
         }
         line += substep;
     }
-}
\ No newline at end of file
+}
+/// Visits the nodes of a level in the order of `traverse_level`, a line segment at a time.
+#[inline]
+pub fn traverse_segments<F>(level: usize, levels: usize, x1: u32, x2: u32, y1: u32, y2: u32, mut f: F)
+where
+    F: FnMut(Segment),
+{
+    let e = levels - level;
+    let step = 1 << e;
+    let substep = 1 << (e - 1);
+    let count = |start: u32, spacing: u32| if start < x2 { (x2 - start).div_ceil(spacing) } else { 0 };
+
+    let mut line = y1;
+    while line < y2 {
+        let start = x1 + substep;
+        f(Segment { line, start, spacing: step, count: count(start, step) });
+
+        line += substep;
+        if line >= y2 {
+            break;
+        }
+
+        f(Segment { line, start: x1, spacing: substep, count: count(x1, substep) });
+        line += substep;
+    }
+}
+
+/// Pixels of the image at the nodes of the segment.
+#[inline(always)]
+pub fn segment_pixels(image: &GrayImage, segment: Segment) -> impl Iterator<Item = &u8> {
+    let width = image.width() as usize;
+    let line = segment.line as usize * width;
+    let row = &image.as_ref()[line..line + width];
+    row.iter().skip(segment.start as usize).step_by(segment.spacing as usize).take(segment.count as usize)
+}
+
+#[inline(always)]
+pub fn segment_pixels_mut(image: &mut GrayImage, segment: Segment) -> impl Iterator<Item = &mut u8> {
+    let width = image.width() as usize;
+    let line = segment.line as usize * width;
+    let row = &mut (**image)[line..line + width];
+    row.iter_mut().skip(segment.start as usize).step_by(segment.spacing as usize).take(segment.count as usize)
+}
+
+// Lines of a level predicted by one thread
+#[cfg(feature = "parallel")]
+const BAND_LINES: u32 = 64;
+
+/// Visits the nodes of a level a line segment at a time, `y1` must be aligned to its step.
+/// `predict` appends the values of a segment to a buffer reading only coarser levels from `state`,
+/// so with the `parallel` feature it runs on bands of lines in parallel. `apply` then stores
+/// the values of every segment into `state` in traversal order.
+#[cfg(feature = "parallel")]
+pub fn traverse_segments_with<S, P, A>(
+    level: usize,
+    levels: usize,
+    (x1, x2, y1, y2): (u32, u32, u32, u32),
+    state: &mut S,
+    predict: P,
+    mut apply: A,
+) where
+    S: Sync,
+    P: Fn(Segment, &S, &mut Vec<u8>) + Sync + Send,
+    A: FnMut(Segment, &[u8], &mut S),
+{
+    let step = 1u32 << (levels - level);
+    let band = BAND_LINES.div_ceil(step).max(1).saturating_mul(step);
+    let shared = &*state;
+    let bands = map_ordered((y1..y2).step_by(band as usize).collect(), |line| {
+        let (mut segments, mut values) = (Vec::new(), Vec::new());
+        traverse_segments(level, levels, x1, x2, line, line.saturating_add(band).min(y2), |segment| {
+            let start = values.len();
+            predict(segment, shared, &mut values);
+            segments.push((segment, start..values.len()));
+        });
+        (segments, values)
+    });
+
+    for (segments, values) in bands {
+        for (segment, range) in segments {
+            apply(segment, &values[range], state);
+        }
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+#[inline(always)]
+pub fn traverse_segments_with<S, P, A>(
+    level: usize,
+    levels: usize,
+    (x1, x2, y1, y2): (u32, u32, u32, u32),
+    state: &mut S,
+    predict: P,
+    mut apply: A,
+) where
+    P: Fn(Segment, &S, &mut Vec<u8>),
+    A: FnMut(Segment, &[u8], &mut S),
+{
+    let mut values = Vec::new();
+    traverse_segments(level, levels, x1, x2, y1, y2, |segment| {
+        values.clear();
+        predict(segment, state, &mut values);
+        apply(segment, &values, state)
+    });
+}